@@ -0,0 +1,118 @@
+//! Access to the controller registers.
+
+use tom_device::{read_reg, write_reg};
+
+/// Register access of the driver, by byte offset from the controller base.
+///
+/// [`Mmio`] goes to the memory-mapped controller; other implementations can
+/// stand in for it, e.g. to script register values off target.
+pub trait RegisterBus {
+    fn read32(&self, offset: usize) -> u32;
+    fn write32(&self, offset: usize, value: u32);
+    fn read8(&self, offset: usize) -> u8;
+    fn write8(&self, offset: usize, value: u8);
+}
+
+/// The controller registers mapped at `base`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    base: usize,
+}
+
+impl Mmio {
+    pub const fn new(base: usize) -> Self {
+        Self { base }
+    }
+}
+
+impl RegisterBus for Mmio {
+    fn read32(&self, offset: usize) -> u32 {
+        read_reg::<u32>(self.base, offset)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        write_reg::<u32>(self.base, offset, value)
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        read_reg::<u8>(self.base, offset)
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        write_reg::<u8>(self.base, offset, value)
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockBus;
+
+#[cfg(test)]
+mod mock {
+    use super::RegisterBus;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::vec::Vec;
+
+    /// Register bus of the unit tests.
+    ///
+    /// A read returns the values queued for its offset with [`Self::script`]
+    /// in turn, then the one given with [`Self::set`], 0 if none. Writes only
+    /// get recorded, so registers do not change on their own.
+    #[derive(Default)]
+    pub(crate) struct MockBus {
+        values: RefCell<BTreeMap<usize, u32>>,
+        scripts: RefCell<BTreeMap<usize, VecDeque<u32>>>,
+        reads: RefCell<BTreeMap<usize, usize>>,
+        writes: RefCell<Vec<(usize, u32)>>,
+    }
+
+    impl MockBus {
+        pub fn set(&self, offset: usize, value: u32) {
+            self.values.borrow_mut().insert(offset, value);
+        }
+
+        pub fn script(&self, offset: usize, values: &[u32]) {
+            let mut scripts = self.scripts.borrow_mut();
+            scripts.entry(offset).or_default().extend(values);
+        }
+
+        /// Number of reads of the register at `offset`.
+        pub fn reads(&self, offset: usize) -> usize {
+            self.reads.borrow().get(&offset).copied().unwrap_or(0)
+        }
+
+        /// Values written to the register at `offset`, oldest first.
+        pub fn writes(&self, offset: usize) -> Vec<u32> {
+            let writes = self.writes.borrow();
+            writes
+                .iter()
+                .filter(|(o, _)| *o == offset)
+                .map(|(_, v)| *v)
+                .collect()
+        }
+    }
+
+    impl RegisterBus for MockBus {
+        fn read32(&self, offset: usize) -> u32 {
+            *self.reads.borrow_mut().entry(offset).or_default() += 1;
+            let scripted = self
+                .scripts
+                .borrow_mut()
+                .get_mut(&offset)
+                .and_then(VecDeque::pop_front);
+            scripted.unwrap_or_else(|| self.values.borrow().get(&offset).copied().unwrap_or(0))
+        }
+
+        fn write32(&self, offset: usize, value: u32) {
+            self.writes.borrow_mut().push((offset, value));
+        }
+
+        fn read8(&self, offset: usize) -> u8 {
+            (self.read32(offset & !3) >> (8 * (offset & 3))) as u8
+        }
+
+        fn write8(&self, offset: usize, value: u8) {
+            self.writes.borrow_mut().push((offset, u32::from(value)));
+        }
+    }
+}
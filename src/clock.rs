@@ -0,0 +1,98 @@
+//! Time source of the driver.
+
+use tom_timer::{Delay, MillisCountDown, Ticker};
+
+/// Busy waits and timeouts of the driver.
+///
+/// [`TickerClock`] runs them on a `tom_timer` ticker; other implementations
+/// can stand in for it off target, next to a scripted register bus.
+pub trait Clock {
+    /// A running timeout, see [`Self::count_down`].
+    type CountDown: CountDown;
+
+    fn spin_micros(&self, us: u64);
+    fn spin_millis(&self, ms: u64);
+    /// Start a timeout of `ms` milliseconds.
+    fn count_down(&self, ms: u64) -> Self::CountDown;
+}
+
+/// A timeout started by [`Clock::count_down`].
+pub trait CountDown {
+    /// Whether the time is up.
+    fn timeout(&self) -> bool;
+}
+
+/// The clock of a `tom_timer` ticker.
+pub struct TickerClock {
+    ticker: &'static dyn Ticker,
+    delay: Delay,
+}
+
+impl TickerClock {
+    pub const fn new(ticker: &'static dyn Ticker) -> Self {
+        Self {
+            ticker,
+            delay: Delay::new(ticker),
+        }
+    }
+}
+
+impl Clock for TickerClock {
+    type CountDown = MillisCountDown;
+
+    fn spin_micros(&self, us: u64) {
+        self.delay.spin_micros(us)
+    }
+
+    fn spin_millis(&self, ms: u64) {
+        self.delay.spin_millis(ms)
+    }
+
+    fn count_down(&self, ms: u64) -> MillisCountDown {
+        MillisCountDown::new(ms, self.ticker)
+    }
+}
+
+impl CountDown for MillisCountDown {
+    fn timeout(&self) -> bool {
+        MillisCountDown::timeout(self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use super::{Clock, CountDown};
+    use core::cell::Cell;
+
+    /// Polls after which a count down of [`MockClock`] times out.
+    const POLLS: u32 = 1000;
+
+    /// Clock of the unit tests: waits return at once, and every count down
+    /// times out after [`POLLS`] polls whatever its length.
+    pub(crate) struct MockClock;
+
+    pub(crate) struct MockCountDown(Cell<u32>);
+
+    impl Clock for MockClock {
+        type CountDown = MockCountDown;
+
+        fn spin_micros(&self, _us: u64) {}
+
+        fn spin_millis(&self, _ms: u64) {}
+
+        fn count_down(&self, _ms: u64) -> MockCountDown {
+            MockCountDown(Cell::new(POLLS))
+        }
+    }
+
+    impl CountDown for MockCountDown {
+        fn timeout(&self) -> bool {
+            let left = self.0.get();
+            self.0.set(left.saturating_sub(1));
+            left == 0
+        }
+    }
+}
@@ -1,20 +1,47 @@
+use crate::err::CardError;
 use crate::reg::CmdMask;
 use core::fmt::Debug;
 
 use super::sd_reg::{CardStatus, Cic, Cid, Csd, Ocr, Rca};
 
+const SEND_OP_COND: u32 = 1;
 const ALL_SEND_CID: u32 = 2;
 const SEND_RCA: u32 = 3;
+const IO_SEND_OP_COND: u32 = 5;
 const SWITCH_FUNCTION: u32 = 6;
 const SELECT_CARD: u32 = 7;
 const SEND_IF_COND: u32 = 8;
 const SEND_CSD: u32 = 9;
+const SEND_CID: u32 = 10;
+const VOLTAGE_SWITCH: u32 = 11;
 const STOP_TRANSMISSION: u32 = 12;
+const SEND_STATUS: u32 = 13;
 const READ_SINGLE_BLOCK: u32 = 17;
+const READ_MULTIPLE_BLOCK: u32 = 18;
+const SEND_TUNING_BLOCK: u32 = 19;
+const SET_BLOCK_COUNT: u32 = 23;
 const WRITE_SINGLE_BLOCK: u32 = 24;
+const WRITE_MULTIPLE_BLOCK: u32 = 25;
+const SET_WRITE_PROT: u32 = 28;
+const CLR_WRITE_PROT: u32 = 29;
+const SEND_WRITE_PROT: u32 = 30;
+const ERASE_WR_BLK_START: u32 = 32;
+const ERASE_WR_BLK_END: u32 = 33;
+const ERASE: u32 = 38;
 const APP_CMD: u32 = 55;
 const ACMD_SD_SEND_OP_COND: u32 = 41;
 const ACMD_SET_BUS: u32 = 6;
+const ACMD_SD_STATUS: u32 = 13;
+const ACMD_SET_WR_BLK_ERASE_COUNT: u32 = 23;
+const ACMD_SEND_SCR: u32 = 51;
+/// Data returned by CMD19 on a 4-bit bus.
+pub const TUNING_BLOCK_4BIT: [u8; 64] = [
+    0xff, 0x0f, 0xff, 0x00, 0xff, 0xcc, 0xc3, 0xcc, 0xc3, 0x3c, 0xcc, 0xff, 0xfe, 0xff, 0xfe, 0xef,
+    0xff, 0xdf, 0xff, 0xdd, 0xff, 0xfb, 0xff, 0xfb, 0xbf, 0xff, 0x7f, 0xff, 0x77, 0xf7, 0xbd, 0xef,
+    0xff, 0xf0, 0xff, 0xf0, 0x0f, 0xfc, 0xcc, 0x3c, 0xcc, 0x33, 0xcc, 0xcf, 0xff, 0xef, 0xff, 0xee,
+    0xff, 0xfd, 0xff, 0xfd, 0xdf, 0xff, 0xbf, 0xff, 0xbb, 0xff, 0xf7, 0xff, 0xf7, 0x7f, 0x7b, 0xde,
+];
+
 #[derive(Clone, Copy, Default)]
 pub struct Command {
     reg_flags: u32,
@@ -69,6 +96,13 @@ impl Command {
         self.reg_flags | self.index
     }
 
+    /// Have the controller send CMD12 by itself once the data of this
+    /// CMD18 or CMD25 has been transferred, signalled by ACD.
+    pub fn with_auto_stop(mut self) -> Self {
+        self.reg_flags |= CmdMask::send_auto_stop.bits();
+        self
+    }
+
     pub fn arg(&self) -> u32 {
         self.arg
     }
@@ -80,9 +114,66 @@ impl Command {
         self.resp_ty != ResponseType::Non
     }
 
+    pub fn write_mode(&self) -> bool {
+        self.reg_flags & CmdMask::write.bits() != 0
+    }
+
+    /// The response is an R1 or R1b card status.
+    pub fn resp_r1(&self) -> bool {
+        matches!(self.resp_ty, ResponseType::R1 | ResponseType::R1b)
+    }
+
+    pub fn busy_exp(&self) -> bool {
+        self.resp_ty == ResponseType::R1b
+    }
+
     pub fn resp_lang(&self) -> bool {
         self.resp_ty == ResponseType::R2
     }
+
+    /// The response is the R6 published RCA of CMD3.
+    pub fn resp_r6(&self) -> bool {
+        self.resp_ty == ResponseType::R6
+    }
+}
+
+/// Argument of CMD6 (SWITCH_FUNC). Function groups that are not set stay at
+/// 0xF, which means "no change".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cmd6Arg(u32);
+
+impl Cmd6Arg {
+    /// Mode 0: query which functions the card supports.
+    pub const fn check() -> Self {
+        Self(0x00FF_FFFF)
+    }
+
+    /// Mode 1: switch to the selected functions.
+    pub const fn switch() -> Self {
+        Self(0x80FF_FFFF)
+    }
+
+    /// Select `function` in function `group`, 1 to 6.
+    pub const fn group(self, group: u8, function: u8) -> Self {
+        assert!(matches!(group, 1..=6));
+        let shift = (group as u32 - 1) * 4;
+        Self(self.0 & !(0xF << shift) | (function as u32 & 0xF) << shift)
+    }
+
+    /// Whether the argument switches rather than queries.
+    pub const fn is_switch(&self) -> bool {
+        self.0 & 0x8000_0000 != 0
+    }
+
+    /// Function given for `group`, 0xF when the group is left unchanged.
+    pub const fn function(&self, group: u8) -> u8 {
+        assert!(matches!(group, 1..=6));
+        (self.0 >> ((group as u32 - 1) * 4)) as u8 & 0xF
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -92,8 +183,12 @@ pub enum ResponseType {
     R1b = 10,
     R2 = 2,
     R3 = 3,
+    R4 = 4,
     R6 = 6,
     R7 = 7,
+    /// Short response taken as a raw word, without the R1 status checks,
+    /// e.g. the task bitmap of CMD13 SEND_TASK_STATUS
+    R48 = 48,
 }
 
 impl Default for ResponseType {
@@ -109,12 +204,15 @@ impl Debug for ResponseType {
             Self::R1b => write!(f, "R1b"),
             Self::R2 => write!(f, "R2"),
             Self::R3 => write!(f, "R3"),
+            Self::R4 => write!(f, "R4"),
             Self::R6 => write!(f, "R6"),
             Self::R7 => write!(f, "R7"),
+            Self::R48 => write!(f, "R48"),
         }
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Response {
     Rz,
     R48(u32),
@@ -128,37 +226,69 @@ impl Response {
             _ => CardStatus::default(),
         }
     }
-    pub(crate) fn csd(self) -> Csd {
+    /// Whether an R2 response to `cmd` matches its CRC7, checked as the CSD
+    /// for CMD9 and as the CID for CMD2 and CMD10. The controller keeps no
+    /// CRC of short responses, so those always match.
+    pub(crate) fn crc_matches(self, cmd: Command) -> bool {
+        match self {
+            Self::R136(r) if cmd.index == SEND_CSD => Csd::from(r).crc_matches(),
+            Self::R136(r) => Cid::from(r).is_valid(),
+            _ => true,
+        }
+    }
+    /// The CSD of an R2 response, checked against its CRC7 since the
+    /// controller's own R2 CRC check is unreliable on some revisions. Unless
+    /// `mmc`, CSD_STRUCTURE must not be the value 3 reserved on SD; eMMC
+    /// uses it for a version coded in the Ext CSD.
+    pub(crate) fn csd(self, mmc: bool) -> Result<Csd, CardError> {
+        let Self::R136(r) = self else {
+            return Err(CardError::InvalidResponse);
+        };
+        let csd = Csd::from(r);
+        if !csd.crc_matches() {
+            return Err(CardError::ResponseCrcMismatch);
+        }
+        if !mmc && !csd.is_valid() {
+            return Err(CardError::InvalidResponse);
+        }
+        Ok(csd)
+    }
+    /// The CID of an R2 response, checked against its CRC7.
+    pub(crate) fn cid(self) -> Result<Cid, CardError> {
         match self {
-            Self::R136(r) => Csd::from(r),
-            _ => Csd::default(),
+            Self::R136(r) => Some(Cid::from(r))
+                .filter(Cid::is_valid)
+                .ok_or(CardError::ResponseCrcMismatch),
+            _ => Err(CardError::InvalidResponse),
         }
     }
-    pub(crate) fn cid(self) -> Cid {
+
+    pub(crate) fn ocr(self) -> Option<Ocr> {
         match self {
-            Self::R136(r) => Cid::from(r),
-            _ => Cid::default(),
+            Response::R48(r) => Some(Ocr::from(r)).filter(Ocr::is_valid),
+            _ => None,
         }
     }
 
-    pub(crate) fn ocr(self) -> Ocr {
+    pub(crate) fn cic(self) -> Option<Cic> {
         match self {
-            Response::R48(r) => Ocr::from(r),
-            _ => Ocr::default(),
+            Response::R48(r) => Some(Cic::from(r)).filter(Cic::is_valid),
+            _ => None,
         }
     }
 
-    pub(crate) fn cic(self) -> Cic {
+    /// R4 of CMD5: I/O OCR, function count and memory present flag.
+    pub(crate) fn io_ocr(self) -> Option<u32> {
         match self {
-            Response::R48(r) => Cic::from(r),
-            _ => Cic::default(),
+            Response::R48(r) => Some(r),
+            _ => None,
         }
     }
 
-    pub(crate) fn rca(self) -> Rca {
+    pub(crate) fn rca(self) -> Option<Rca> {
         match self {
-            Response::R48(r) => Rca::from(r),
-            _ => Rca::default(),
+            Response::R48(r) => Some(Rca::from(r)).filter(Rca::is_valid),
+            _ => None,
         }
     }
 }
@@ -181,6 +311,13 @@ pub fn up_clk() -> Command {
     cmd
 }
 
+/// CMD11: Switch the signal voltage to 1.8 V
+pub fn voltage_switch() -> Command {
+    let mut cmd = Command::no_data_cmd_r48(VOLTAGE_SWITCH, ResponseType::R1, 0);
+    cmd.reg_flags |= CmdMask::volt_switch.bits();
+    cmd
+}
+
 /// CMD12: Stop transmission
 pub fn stop_transmission() -> Command {
     let mut cmd = Command::default();
@@ -195,6 +332,24 @@ pub fn stop_transmission() -> Command {
     cmd
 }
 
+/// CMD12 with the HPI bit: interrupt an eMMC background operation
+pub fn stop_transmission_hpi(rca: u16) -> Command {
+    let arg = u32::from(rca) << 16 | 1;
+    Command::no_data_cmd_r48(STOP_TRANSMISSION, ResponseType::R1b, arg)
+}
+
+/// CMD13: Send card status, with the HPI bit set for eMMC interrupts
+pub fn send_status(rca: u16, hpi: bool) -> Command {
+    let arg = u32::from(rca) << 16 | u32::from(hpi);
+    Command::no_data_cmd_r48(SEND_STATUS, ResponseType::R1, arg)
+}
+
+/// CMD13 with SEND_TASK_STATUS: Send the command queue task status
+pub fn send_task_status(rca: u16) -> Command {
+    let arg = u32::from(rca) << 16 | 1 << 15;
+    Command::no_data_cmd_r48(SEND_STATUS, ResponseType::R48, arg)
+}
+
 /// CMD2: Ask any card to send their CID
 pub fn all_send_cid() -> Command {
     let mut cmd = Command::no_data_cmd_r48(ALL_SEND_CID, ResponseType::R2, 0);
@@ -202,9 +357,28 @@ pub fn all_send_cid() -> Command {
     cmd
 }
 
+/// CMD5: Ask an SDIO card for its I/O OCR, arg 0 only inquires
+pub fn io_send_op_cond(ocr: u32) -> Command {
+    Command {
+        reg_flags: CmdMask::start_cmd.bits()
+            | CmdMask::use_hold_reg.bits()
+            | CmdMask::wait_prvdata_complete.bits()
+            | CmdMask::response_expect.bits(),
+        index: IO_SEND_OP_COND,
+        arg: ocr,
+        resp_ty: ResponseType::R4,
+    }
+}
+
 /// CMD6: switch function
-pub fn switch_function(arg: u32) -> Command {
-    Command::no_data_cmd_r48(SWITCH_FUNCTION, ResponseType::R1, arg)
+pub fn switch_function(arg: Cmd6Arg) -> Command {
+    Command::transfer_cmd(SWITCH_FUNCTION, ResponseType::R1, arg.bits(), false)
+}
+
+/// CMD6 on eMMC: SWITCH, writing `value` to Ext CSD byte `index`
+pub fn mmc_switch(index: u8, value: u8) -> Command {
+    let arg = 3 << 24 | u32::from(index) << 16 | u32::from(value) << 8;
+    Command::no_data_cmd_r48(SWITCH_FUNCTION, ResponseType::R1b, arg)
 }
 
 /// CMD7: Select or deselect card
@@ -213,6 +387,16 @@ pub fn select_card(rca: u16) -> Command {
     Command::no_data_cmd_r48(SELECT_CARD, ResponseType::R1b, arg)
 }
 
+/// CMD7 with RCA 0: Deselect all cards, no response
+pub fn deselect_card() -> Command {
+    let mut cmd = Command::default();
+    cmd.reg_flags |= CmdMask::start_cmd.bits()
+        | CmdMask::use_hold_reg.bits()
+        | CmdMask::wait_prvdata_complete.bits();
+    cmd.index = SELECT_CARD;
+    cmd
+}
+
 /// CMD9: Send CSD
 pub fn send_csd(rca: u16) -> Command {
     let arg = u32::from(rca) << 16;
@@ -221,21 +405,103 @@ pub fn send_csd(rca: u16) -> Command {
     cmd
 }
 
+/// CMD10: Send CID of the addressed card
+pub fn send_cid(rca: u16) -> Command {
+    let arg = u32::from(rca) << 16;
+    let mut cmd = Command::no_data_cmd_r48(SEND_CID, ResponseType::R2, arg);
+    cmd.reg_flags |= CmdMask::response_length.bits();
+    cmd
+}
+
 /// CMD17: Read a single block from the card
 pub fn read_single_block(addr: u32) -> Command {
     Command::transfer_cmd(READ_SINGLE_BLOCK, ResponseType::R1, addr, false)
 }
 
+/// CMD18: Read blocks until stopped by CMD12
+pub fn read_multiple_block(addr: u32) -> Command {
+    Command::transfer_cmd(READ_MULTIPLE_BLOCK, ResponseType::R1, addr, false)
+}
+
+/// CMD19: Send the tuning block, SDR50 and SDR104 only
+pub fn send_tuning_block() -> Command {
+    Command::transfer_cmd(SEND_TUNING_BLOCK, ResponseType::R1, 0, false)
+}
+
+/// CMD23: Set the block count of the next CMD18 or CMD25, which then ends
+/// without CMD12
+pub fn set_block_count(count: u32) -> Command {
+    Command::no_data_cmd_r48(SET_BLOCK_COUNT, ResponseType::R1, count)
+}
+
 /// CMD24: Write block
 pub fn write_single_block(addr: u32) -> Command {
     Command::transfer_cmd(WRITE_SINGLE_BLOCK, ResponseType::R1, addr, true)
 }
 
+/// CMD25: Write blocks until stopped by CMD12
+pub fn write_multiple_block(addr: u32) -> Command {
+    Command::transfer_cmd(WRITE_MULTIPLE_BLOCK, ResponseType::R1, addr, true)
+}
+
+/// CMD28: Set write protection of the group containing `addr`
+pub fn set_write_prot(addr: u32) -> Command {
+    Command::no_data_cmd_r48(SET_WRITE_PROT, ResponseType::R1b, addr)
+}
+
+/// CMD29: Clear write protection of the group containing `addr`
+pub fn clr_write_prot(addr: u32) -> Command {
+    Command::no_data_cmd_r48(CLR_WRITE_PROT, ResponseType::R1b, addr)
+}
+
+/// CMD30: Send the protection bits of the 32 groups starting at `addr`
+pub fn send_write_prot(addr: u32) -> Command {
+    Command::transfer_cmd(SEND_WRITE_PROT, ResponseType::R1, addr, false)
+}
+
+/// CMD32: Set the address of the first block to erase
+pub fn erase_wr_blk_start(addr: u32) -> Command {
+    Command::no_data_cmd_r48(ERASE_WR_BLK_START, ResponseType::R1, addr)
+}
+
+/// CMD33: Set the address of the last block to erase
+pub fn erase_wr_blk_end(addr: u32) -> Command {
+    Command::no_data_cmd_r48(ERASE_WR_BLK_END, ResponseType::R1, addr)
+}
+
+/// CMD38: Erase the selected blocks
+pub fn erase() -> Command {
+    Command::no_data_cmd_r48(ERASE, ResponseType::R1b, 0)
+}
+
 /// CMD55: App Command. Indicates that next command will be a app command
 pub fn app_cmd(rca: u16) -> Command {
     Command::no_data_cmd_r48(APP_CMD, ResponseType::R1, u32::from(rca) << 16)
 }
 
+/// CMD1 on eMMC: Send the host OCR and return the device OCR, R3
+pub fn send_op_cond(ocr: u32) -> Command {
+    Command {
+        reg_flags: CmdMask::start_cmd.bits()
+            | CmdMask::use_hold_reg.bits()
+            | CmdMask::wait_prvdata_complete.bits()
+            | CmdMask::response_expect.bits(),
+        index: SEND_OP_COND,
+        arg: ocr,
+        resp_ty: ResponseType::R3,
+    }
+}
+
+/// CMD3 on eMMC: Assign relative address `rca` to the device
+pub fn set_relative_address(rca: u16) -> Command {
+    Command::no_data_cmd_r48(SEND_RCA, ResponseType::R1, u32::from(rca) << 16)
+}
+
+/// CMD8 on eMMC: Read the 512 byte Ext CSD
+pub fn send_ext_csd() -> Command {
+    Command::transfer_cmd(SEND_IF_COND, ResponseType::R1, 0, false)
+}
+
 /// CMD3: Send RCA
 pub fn send_relative_address() -> Command {
     Command::no_data_cmd_r48(SEND_RCA, ResponseType::R6, 0)
@@ -251,6 +517,21 @@ pub fn set_bus_width(arg: u32) -> Command {
     Command::no_data_cmd_r48(ACMD_SET_BUS, ResponseType::R1, arg)
 }
 
+/// ACMD23: Number of blocks, 23 bits, to pre-erase before the next CMD25
+pub fn set_wr_blk_erase_count(count: u32) -> Command {
+    Command::no_data_cmd_r48(ACMD_SET_WR_BLK_ERASE_COUNT, ResponseType::R1, count)
+}
+
+/// ACMD13: Send the 64 byte SD Status over the data lines
+pub fn sd_status() -> Command {
+    Command::transfer_cmd(ACMD_SD_STATUS, ResponseType::R1, 0, false)
+}
+
+/// ACMD51: Send the 8 byte SCR over the data lines
+pub fn send_scr() -> Command {
+    Command::transfer_cmd(ACMD_SEND_SCR, ResponseType::R1, 0, false)
+}
+
 /// ACMD41: App Op Command
 pub fn sd_send_op_cond(host_high_capacity_support: bool, sr18: bool) -> Command {
     let mut cmd = Command::default();
@@ -264,3 +545,45 @@ pub fn sd_send_op_cond(host_high_capacity_support: bool, sr18: bool) -> Command
         | CmdMask::response_expect.bits();
     cmd
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CSD of an eMMC 4.x device: CSD_STRUCTURE 3, SPEC_VERS 4
+    const CSD_EMMC: u128 = 0xD027_0132_0F59_03FF_F6DB_FFEF_8E40_400D;
+
+    fn r2(reg: u128) -> Response {
+        Response::R136((
+            reg as u32,
+            (reg >> 32) as u32,
+            (reg >> 64) as u32,
+            (reg >> 96) as u32,
+        ))
+    }
+
+    #[test]
+    fn r2_crc_checked_as_the_register_sent() {
+        // MID 0x03, OID "SD", PNM "SU02G", PRV 8.0, PSN 0x12345678, MDT 2013-06
+        let cid = r2(0x0353_4453_5530_3247_8012_3456_7800_D619);
+        assert!(cid.crc_matches(send_cid(1)));
+        assert!(cid.crc_matches(all_send_cid()));
+        let csd = r2(CSD_EMMC);
+        assert!(csd.crc_matches(send_csd(1)));
+        assert!(!r2(CSD_EMMC ^ 1 << 100).crc_matches(send_csd(1)));
+    }
+
+    #[test]
+    fn emmc_csd_with_structure_in_ext_csd() {
+        let csd = r2(CSD_EMMC).csd(true).unwrap();
+        assert_eq!(csd.version(), 3);
+        assert!(matches!(
+            r2(CSD_EMMC).csd(false),
+            Err(CardError::InvalidResponse)
+        ));
+        assert!(matches!(
+            r2(CSD_EMMC ^ 1 << 64).csd(true),
+            Err(CardError::ResponseCrcMismatch)
+        ));
+    }
+}
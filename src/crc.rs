@@ -0,0 +1,46 @@
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), as used by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// CRC7 (polynomial x^7 + x^3 + 1) protecting SD command and response tokens.
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let inv = ((byte >> bit) & 1) ^ ((crc >> 6) & 1);
+            crc = (crc << 1) & 0x7F;
+            if inv != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc7_spec_examples() {
+        // CMD0, CMD17 and the R1 of CMD17 from the SD physical layer spec
+        assert_eq!(crc7(&[0x40, 0x00, 0x00, 0x00, 0x00]), 0x4A);
+        assert_eq!(crc7(&[0x51, 0x00, 0x00, 0x00, 0x00]), 0x2A);
+        assert_eq!(crc7(&[0x11, 0x00, 0x00, 0x09, 0x00]), 0x33);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -1,6 +1,7 @@
 use tom_device::DeviceError;
 
-use super::reg::InterruptMask;
+use super::reg::{InterruptMask, RegisterSnapshot};
+use super::sd_reg::CardStatus;
 use core::{
     error::Error,
     fmt::{Debug, Display},
@@ -11,8 +12,102 @@ pub enum CardError {
     CardInitErr,
     InterruptErr(Interrupt),
     TimeoutErr(Timeout),
+    /// Error bit set in the R1 card status of the command
+    StatusErr(StatusError),
     VoltagePattern,
     DataTransferTimeout,
+    UnsupportedSpeedMode,
+    TuningFailed,
+    InvalidResponse,
+    /// The CRC7 carried in a CID or CSD response does not match its content
+    ResponseCrcMismatch,
+    /// The card flagged COM_CRC_ERROR: the previous command reached it
+    /// with a bad CRC and was ignored
+    CommandCrc,
+    BufferLength,
+    Unsupported,
+    CapacityMismatch,
+    ClockNotRunning,
+    /// No start bit on the data lines when a read block was due, typically a
+    /// wrong sample phase in DDR modes
+    StartBitError,
+    /// DMA buffer above 4 GiB or not word aligned
+    DmaAddress,
+    /// Missing descriptor or card error summary reported by the IDMAC
+    DmaFault,
+    /// The IDMAC got a bus error fetching descriptor `desc` or the buffer
+    /// it points to; the address is bad, a retry fails the same way
+    DmaBusError {
+        desc: u32,
+    },
+    /// The FIFO was not emptied or filled fast enough
+    HostStarvationTimeout,
+    /// The card did not deliver read data in time
+    DataReadTimeout,
+    /// The card reported WP_VIOLATION for a write
+    WriteProtected,
+    /// Argument rejected before sending command `cmd`
+    InvalidArgument {
+        cmd: u8,
+        arg: usize,
+    },
+    /// The CMD11 signal voltage switch did not complete; the card must be
+    /// power cycled before it is identified again
+    VoltageSwitch,
+    /// Data left in the FIFO after the whole transfer was read
+    DataOverrun,
+    /// A block transfer still failed with `last` after `attempts` tries
+    RetriesExhausted {
+        attempts: u8,
+        last: Transient,
+    },
+}
+
+/// Point of the init sequence at which a failure happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStep {
+    ControllerReset,
+    SetClock,
+    GoIdle,
+    SendIfCond,
+    IoSendOpCond,
+    SendOpCond,
+    VoltageSwitch,
+    AllSendCid,
+    SendRelativeAddr,
+    SendCsd,
+    SelectCard,
+    ReadExtCsd,
+    SwitchFunction,
+    SetBusWidth,
+}
+
+impl InitStep {
+    pub const ALL: [InitStep; 14] = [
+        Self::ControllerReset,
+        Self::SetClock,
+        Self::GoIdle,
+        Self::SendIfCond,
+        Self::IoSendOpCond,
+        Self::SendOpCond,
+        Self::VoltageSwitch,
+        Self::AllSendCid,
+        Self::SendRelativeAddr,
+        Self::SendCsd,
+        Self::SelectCard,
+        Self::ReadExtCsd,
+        Self::SwitchFunction,
+        Self::SetBusWidth,
+    ];
+}
+
+/// What went wrong during `init`, kept because the `DeviceError` returned to
+/// the caller loses it.
+#[derive(Debug, Clone, Copy)]
+pub struct InitFailure {
+    pub step: InitStep,
+    pub error: CardError,
+    pub regs: RegisterSnapshot,
 }
 
 impl Error for CardError {
@@ -34,7 +129,34 @@ impl Display for CardError {
             Self::DataTransferTimeout => write!(f, "Data transfer timeout!"),
             Self::InterruptErr(itr) => write!(f, "{}", itr),
             Self::TimeoutErr(to) => write!(f, "{}", to),
+            Self::StatusErr(err) => write!(f, "{}", err),
             Self::VoltagePattern => write!(f, "Card voltage pattern failed!"),
+            Self::UnsupportedSpeedMode => write!(f, "Speed mode not supported!"),
+            Self::TuningFailed => write!(f, "No working sample phase found!"),
+            Self::InvalidResponse => write!(f, "Card response malformed!"),
+            Self::ResponseCrcMismatch => write!(f, "Card register CRC7 mismatch!"),
+            Self::CommandCrc => write!(f, "Card received a command with a bad CRC!"),
+            Self::BufferLength => write!(f, "Buffer is not a whole number of blocks!"),
+            Self::Unsupported => write!(f, "Not supported by the card or controller!"),
+            Self::CapacityMismatch => write!(f, "Card reads past its reported capacity!"),
+            Self::ClockNotRunning => write!(f, "Card clock is not enabled!"),
+            Self::StartBitError => write!(f, "Card data start bit error!"),
+            Self::DmaAddress => write!(f, "DMA buffer address not usable!"),
+            Self::DmaFault => write!(f, "Internal DMA controller fault!"),
+            Self::DmaBusError { desc } => {
+                write!(f, "DMA bus error at descriptor {:#x}!", desc)
+            }
+            Self::HostStarvationTimeout => write!(f, "Host data starvation timeout!"),
+            Self::DataReadTimeout => write!(f, "Card data read timeout!"),
+            Self::WriteProtected => write!(f, "Card is write protected!"),
+            Self::InvalidArgument { cmd, arg } => {
+                write!(f, "CMD{} argument {:#x} out of range!", cmd, arg)
+            }
+            Self::VoltageSwitch => write!(f, "Card 1.8V signal voltage switch failed!"),
+            Self::DataOverrun => write!(f, "Card sent more data than requested!"),
+            Self::RetriesExhausted { attempts, last } => {
+                write!(f, "{} Gave up after {} attempts!", last, attempts)
+            }
         }
     }
 }
@@ -51,12 +173,19 @@ impl From<Interrupt> for CardError {
     }
 }
 
+impl From<StatusError> for CardError {
+    fn from(value: StatusError) -> Self {
+        Self::StatusErr(value)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Timeout {
     WaitReset,
     WaitCmdLine,
     WaitCmdDone,
     WaitDataLine,
+    WaitTranState,
     FifoStatus,
 }
 
@@ -67,6 +196,7 @@ impl Display for Timeout {
             Timeout::WaitCmdLine => write!(f, "Card wait command line timeout!"),
             Timeout::WaitCmdDone => write!(f, "Card wait command done timeout!"),
             Timeout::WaitDataLine => write!(f, "Card wait data line timeout!"),
+            Timeout::WaitTranState => write!(f, "Card wait transfer state timeout!"),
             Timeout::FifoStatus => write!(f, "Card fifo status exception!"),
         }
     }
@@ -126,14 +256,133 @@ impl Display for Interrupt {
     }
 }
 
+/// Block transfer failures that a second attempt may get past: a glitch on
+/// the CMD line or a card that was briefly busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transient {
+    ResponseTimeout,
+    ResponseCrc,
+    DataTransferTimeout,
+}
+
+impl Transient {
+    pub fn classify(err: &CardError) -> Option<Transient> {
+        match err {
+            CardError::InterruptErr(Interrupt::ResponseTimeout) => Some(Self::ResponseTimeout),
+            CardError::InterruptErr(Interrupt::ResponseCrc) => Some(Self::ResponseCrc),
+            CardError::DataTransferTimeout => Some(Self::DataTransferTimeout),
+            _ => None,
+        }
+    }
+}
+
+impl From<Transient> for CardError {
+    fn from(value: Transient) -> Self {
+        match value {
+            Transient::ResponseTimeout => Self::InterruptErr(Interrupt::ResponseTimeout),
+            Transient::ResponseCrc => Self::InterruptErr(Interrupt::ResponseCrc),
+            Transient::DataTransferTimeout => Self::DataTransferTimeout,
+        }
+    }
+}
+
+impl Display for Transient {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", CardError::from(*self))
+    }
+}
+
+/// Error bits of the R1 card status.
+///
+/// WP_VIOLATION is reported as [`CardError::WriteProtected`] instead, and
+/// COM_CRC_ERROR and ILLEGAL_COMMAND are left out: the card sets them for
+/// the previous command, and an optional command answers with
+/// ILLEGAL_COMMAND when it is not implemented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusError {
+    OutOfRange,
+    Address,
+    BlockLen,
+    EraseSeq,
+    EraseParam,
+    LockUnlock,
+    CardEcc,
+    Controller,
+    General,
+    CsdOverwrite,
+    AkeSeq,
+}
+
+impl StatusError {
+    pub fn check(status: &CardStatus) -> Result<(), StatusError> {
+        let errors = [
+            (status.out_of_range(), StatusError::OutOfRange),
+            (status.address_error(), StatusError::Address),
+            (status.block_len_error(), StatusError::BlockLen),
+            (status.erase_seq_error(), StatusError::EraseSeq),
+            (status.erase_param(), StatusError::EraseParam),
+            (status.lock_unlock_failed(), StatusError::LockUnlock),
+            (status.card_ecc_failed(), StatusError::CardEcc),
+            (status.cc_error(), StatusError::Controller),
+            (status.error(), StatusError::General),
+            (status.csd_overwrite(), StatusError::CsdOverwrite),
+            (status.ake_seq_error(), StatusError::AkeSeq),
+        ];
+        match errors.into_iter().find(|&(set, _)| set) {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StatusError::OutOfRange => write!(f, "Card argument out of range!"),
+            StatusError::Address => write!(f, "Card address error!"),
+            StatusError::BlockLen => write!(f, "Card block length error!"),
+            StatusError::EraseSeq => write!(f, "Card erase sequence error!"),
+            StatusError::EraseParam => write!(f, "Card erase parameter error!"),
+            StatusError::LockUnlock => write!(f, "Card lock or unlock failed!"),
+            StatusError::CardEcc => write!(f, "Card internal ecc failed!"),
+            StatusError::Controller => write!(f, "Card internal controller error!"),
+            StatusError::General => write!(f, "Card general error!"),
+            StatusError::CsdOverwrite => write!(f, "Card csd overwrite error!"),
+            StatusError::AkeSeq => write!(f, "Card authentication sequence error!"),
+        }
+    }
+}
+
 impl From<CardError> for DeviceError {
     fn from(value: CardError) -> Self {
         match value {
             CardError::CardInitErr => DeviceError::InvalidConfiguration,
             CardError::InterruptErr(_) => DeviceError::IoError,
             CardError::TimeoutErr(_) => DeviceError::Timeout,
+            CardError::StatusErr(_) => DeviceError::IoError,
             CardError::VoltagePattern => DeviceError::UnsupportedOperation,
-            CardError::DataTransferTimeout => DeviceError::IoError,
+            CardError::DataTransferTimeout => DeviceError::Timeout,
+            CardError::UnsupportedSpeedMode => DeviceError::UnsupportedOperation,
+            CardError::TuningFailed => DeviceError::IoError,
+            CardError::InvalidResponse => DeviceError::IoError,
+            CardError::ResponseCrcMismatch => DeviceError::IoError,
+            CardError::CommandCrc => DeviceError::IoError,
+            CardError::BufferLength => DeviceError::UnsupportedOperation,
+            CardError::Unsupported => DeviceError::UnsupportedOperation,
+            CardError::CapacityMismatch => DeviceError::InvalidConfiguration,
+            CardError::ClockNotRunning => DeviceError::InvalidConfiguration,
+            CardError::StartBitError => DeviceError::IoError,
+            CardError::DmaAddress => DeviceError::UnsupportedOperation,
+            CardError::DmaFault => DeviceError::IoError,
+            CardError::DmaBusError { .. } => DeviceError::InvalidConfiguration,
+            CardError::HostStarvationTimeout => DeviceError::Timeout,
+            CardError::DataReadTimeout => DeviceError::Timeout,
+            // there is no read-only error to report
+            CardError::WriteProtected => DeviceError::UnsupportedOperation,
+            CardError::InvalidArgument { .. } => DeviceError::UnsupportedOperation,
+            CardError::VoltageSwitch => DeviceError::IoError,
+            CardError::DataOverrun => DeviceError::IoError,
+            CardError::RetriesExhausted { last, .. } => DeviceError::from(CardError::from(last)),
         }
     }
 }
@@ -0,0 +1,51 @@
+//! Fixed-size record of driver events, for systems without a `log` backend.
+
+use core::fmt::{self, Write};
+
+/// Bytes kept by the event log; the oldest ones are overwritten first.
+pub const EVENT_LOG_LEN: usize = 512;
+
+/// Ring buffer of event lines, each ended with `\n`. Once it has wrapped,
+/// the first line drained may have lost its beginning.
+pub(crate) struct EventLog {
+    buf: [u8; EVENT_LOG_LEN],
+    start: usize,
+    len: usize,
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; EVENT_LOG_LEN],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buf[(self.start + self.len) % EVENT_LOG_LEN] = byte;
+        if self.len == EVENT_LOG_LEN {
+            self.start = (self.start + 1) % EVENT_LOG_LEN;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Move the oldest bytes into `out` and return how many were moved.
+    pub fn drain(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        for (i, byte) in out[..n].iter_mut().enumerate() {
+            *byte = self.buf[(self.start + i) % EVENT_LOG_LEN];
+        }
+        self.start = (self.start + n) % EVENT_LOG_LEN;
+        self.len -= n;
+        n
+    }
+}
+
+impl Write for EventLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        Ok(())
+    }
+}
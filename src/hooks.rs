@@ -0,0 +1,64 @@
+//! Hooks into the surrounding OS and board.
+
+/// Free-running microsecond counter, for timing the init steps.
+pub type MicrosClock = fn() -> u64;
+
+/// Board control of the CMD line pull-up, through a GPIO or the controller
+/// `CTRL.enable_od_pullup` bit.
+///
+/// `open_drain` is called before CMD0 and must engage the pull-up for the
+/// identification phase; `push_pull` is called once CMD3 has completed.
+pub trait CmdPullup {
+    fn open_drain(&self);
+    fn push_pull(&self);
+}
+
+/// Board control of the card I/O signal voltage, for UHS-I cards.
+///
+/// `set_1v8` is called during the CMD11 sequence with the card clock
+/// stopped, must switch the signalling supply to 1.8 V, and returns whether
+/// the regulator did so.
+pub trait SignalVoltage {
+    fn set_1v8(&self) -> bool;
+}
+
+/// Cache maintenance around IDMAC transfers, for systems where DMA is not
+/// coherent with the CPU caches.
+///
+/// `flush` writes dirty lines of the range back to memory before the
+/// controller reads it: write buffers and the descriptor list. `invalidate`
+/// drops the cached lines before and after the controller writes to memory.
+pub trait DmaCoherency {
+    fn flush(&self, addr: usize, len: usize);
+    fn invalidate(&self, addr: usize, len: usize);
+}
+
+/// Mutual exclusion provided by the OS, e.g. a mutex or a critical section.
+///
+/// `acquire` blocks until the caller holds the lock, `release` gives it back.
+/// The driver nests `acquire` calls, each paired with a `release`, so a task
+/// holding the lock must be able to take it again.
+pub trait CmdLock {
+    fn acquire(&self);
+    fn release(&self);
+}
+
+/// Holds the lock, if any, until dropped.
+pub(crate) struct LockGuard(Option<&'static dyn CmdLock>);
+
+impl LockGuard {
+    pub fn new(lock: Option<&'static dyn CmdLock>) -> Self {
+        if let Some(lock) = lock {
+            lock.acquire();
+        }
+        Self(lock)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(lock) = self.0 {
+            lock.release();
+        }
+    }
+}
@@ -1,23 +1,332 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(error_generic_member_access)]
 #![feature(const_option)]
+mod bus;
+mod clock;
 mod cmd;
+mod crc;
 pub mod err;
+mod event;
+pub mod hooks;
 mod ops;
 mod reg;
 mod sd_reg;
 
 use cmd::*;
+use err::{Interrupt, Timeout};
+use event::EventLog;
+use hooks::{CmdLock, CmdPullup, DmaCoherency, LockGuard, MicrosClock, SignalVoltage};
 
-use log::{debug, info, trace};
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use log::{debug, error, info, trace, warn};
 use ops::*;
 use reg::*;
-use sd_reg::*;
 use tom_device::{
     read_reg, write_reg, BlkDevInfo, BlockDevice, BlockSize, Device, DeviceError, DeviceStatus,
     DeviceType,
 };
 use tom_timer::{Delay, Ticker};
+
+pub use bus::{Mmio, RegisterBus};
+pub use err::{CardError, InitFailure, InitStep, StatusError, Transient};
+pub use event::EVENT_LOG_LEN;
+pub use ops::{ByteCounts, FifoResetPolicy, FifoThreshold};
+pub use reg::RegisterSnapshot;
+pub use sd_reg::{
+    BusWidth, CardStatus, Cic, Cid, Csd, CurrentLimit, CurrentState, DriverStrength, ExtCsd, Ocr,
+    PreEolInfo, Rca, Scr, SdStatus, SpeedMode, Status, StatusFormat, SwitchStatus,
+};
+
+/// Card clock during identification.
+const IDENT_CLOCK_HZ: u32 = 400_000;
+/// Card clock in default speed mode.
+const DEFAULT_CLOCK_HZ: u32 = 25_000_000;
+/// Card clock in high speed mode.
+const HIGH_SPEED_CLOCK_HZ: u32 = 50_000_000;
+/// Floor of a write timeout derived from a measured programming time: the
+/// SD write busy limit for SDSC and SDHC cards.
+const MIN_WRITE_TIMEOUT_MS: u64 = 250;
+/// Blocks read back per CMD18 by [`DwMmcHost::write_blocks_verified`] and
+/// [`DwMmcHost::read_and_verify_manifest`], and written per CMD25 by
+/// [`DwMmcHost::write_zeroes`] without erase.
+const VERIFY_CHUNK_BLOCKS: usize = 8;
+/// Erase busy time allowed per block when the SD Status gives no erase
+/// timeout.
+const ERASE_BLOCK_TIMEOUT_MS: u64 = 250;
+/// IDMAC descriptors held by the host, which bounds one DMA command to
+/// `IDMAC_DESCS * IDMAC_DESC_BUF_LEN` bytes.
+const IDMAC_DESCS: usize = 32;
+
+/// `CLKDIV` value giving the fastest card clock not above `target_hz` from a
+/// `base_hz` input clock.
+///
+/// The controller divides by `2 * div`, or not at all for 0. A target at or
+/// above `base_hz` gives 0; a target too low for the 8-bit divider, 0
+/// included, gives the maximum 0xFF.
+pub fn clock_divider_for(base_hz: u32, target_hz: u32) -> u32 {
+    let (base, target) = (u64::from(base_hz), u64::from(target_hz));
+    match target {
+        0 => 0xFF,
+        t if t >= base => 0,
+        t => base.div_ceil(2 * t).min(0xFF) as u32,
+    }
+}
+
+/// Card clock produced by `CLKDIV` value `div` from a `base_hz` input clock.
+pub fn clock_hz_for(base_hz: u32, div: u32) -> u32 {
+    match div {
+        0 => base_hz,
+        div => base_hz / (2 * div),
+    }
+}
+
+/// Command argument addressing 512-byte block `lba`: the block number on
+/// high-capacity cards, SDHC, SDXC and sector-addressed eMMC, its byte offset
+/// on SDSC and byte-addressed eMMC. The OCR capacity bit tells them apart.
+fn block_address(high_capacity: bool, lba: usize) -> u32 {
+    if high_capacity {
+        lba as u32
+    } else {
+        (lba as u32) << 9
+    }
+}
+
+/// Identity of a card as read during identification.
+#[derive(Debug, Clone, Copy)]
+pub struct CardSummary {
+    pub cid: Cid,
+    pub csd: Csd,
+    pub ocr: Ocr,
+    pub rca: Rca,
+    /// `false` when the CID or CSD was decoded despite a CRC error, see
+    /// [`DwMmcHost::registers_trustworthy`]
+    pub trustworthy: bool,
+}
+
+/// Every decoded register of the card. `scr` and `sd_status` are `None`
+/// until they have been read.
+#[derive(Debug, Clone, Copy)]
+pub struct CardRegisters {
+    pub cid: Cid,
+    pub csd: Csd,
+    pub ocr: Ocr,
+    pub rca: Rca,
+    pub cic: Cic,
+    pub scr: Option<Scr>,
+    pub sd_status: Option<SdStatus>,
+}
+
+/// Age and wear of the card, see [`DwMmcHost::health`].
+#[derive(Debug, Clone, Copy)]
+pub struct CardHealth {
+    /// (year, month) from the CID
+    pub manufacturing_date: (u16, u8),
+    /// `None` for cards without wear reporting, i.e. plain SD
+    pub wear: Option<WearEstimate>,
+}
+
+/// eMMC wear estimate from the Ext CSD.
+#[derive(Debug, Clone, Copy)]
+pub struct WearEstimate {
+    /// Lower bound of the remaining SLC lifetime in percent
+    pub life_remaining_a: Option<u8>,
+    /// Lower bound of the remaining MLC lifetime in percent
+    pub life_remaining_b: Option<u8>,
+    pub pre_eol: PreEolInfo,
+}
+
+/// How card insertion and removal are noticed, see
+/// [`DwMmcHost::poll_card_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CardDetectMode {
+    /// `CDETECT` is read on every poll
+    #[default]
+    Polling,
+    /// The card detect interrupt is unmasked and polling reads the latched
+    /// event, for calling from the controller interrupt handler
+    Interrupt,
+}
+
+/// Change of card presence reported by [`DwMmcHost::poll_card_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardEvent {
+    Inserted,
+    Removed,
+}
+
+/// Most likely cause of a card that does not work, from
+/// [`DwMmcHost::diagnose`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnosis {
+    /// Init, reads at 1-bit and at the widest bus width all succeeded
+    Healthy,
+    /// `CDETECT` reports an empty slot
+    NoCard,
+    /// A card is detected but commands get no response or corrupted ones:
+    /// check CMD, CLK and the CMD pull-up
+    CmdLineFault,
+    /// Commands work but DAT0 stays busy, 1-bit reads always fail or data
+    /// errors only show at the wider bus: check the DAT lines
+    DataLineFault,
+    /// The card answers cleanly but rejects the voltage or never becomes
+    /// ready
+    UnsupportedCard,
+    /// Errors that come and go with the same setup
+    FlakyCard,
+}
+
+impl Diagnosis {
+    /// Classify an error from identification or configuration, where the
+    /// data line is only used for short register reads at 1-bit.
+    fn from_error(err: CardError) -> Self {
+        match err {
+            CardError::InterruptErr(
+                Interrupt::ResponseTimeout
+                | Interrupt::ResponseCrc
+                | Interrupt::ResponseErr
+                | Interrupt::EndBitErr,
+            )
+            | CardError::InvalidResponse
+            | CardError::ResponseCrcMismatch
+            | CardError::CommandCrc
+            | CardError::TimeoutErr(Timeout::WaitCmdLine | Timeout::WaitCmdDone) => {
+                Self::CmdLineFault
+            }
+            CardError::InterruptErr(
+                Interrupt::DataCrc | Interrupt::DataReadTimeout | Interrupt::StartBitErr,
+            )
+            | CardError::TimeoutErr(Timeout::WaitDataLine)
+            | CardError::DataTransferTimeout
+            | CardError::DataReadTimeout
+            | CardError::StartBitError => Self::DataLineFault,
+            CardError::VoltagePattern
+            | CardError::CardInitErr
+            | CardError::Unsupported
+            | CardError::UnsupportedSpeedMode => Self::UnsupportedCard,
+            _ => Self::FlakyCard,
+        }
+    }
+}
+
+/// Signal voltage the card is identified at, and whether it is then switched
+/// to 1.8 V, see [`DwMmcHost::with_init_voltage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InitVoltage {
+    /// 3.3 V throughout: ACMD41 does not ask for 1.8 V and CMD11 is never
+    /// sent
+    V33,
+    /// 1.8 V from power-up, for boards without a 3.3 V signal supply: the
+    /// regulator and `UHS_REG` are set to 1.8 V before power is enabled and
+    /// eMMC devices are offered only the 1.70-1.95 V window
+    V18,
+    /// Identify at 3.3 V, ask for 1.8 V with S18R and run the CMD11 switch
+    /// when the card accepts and a regulator is set
+    #[default]
+    V33ThenV18,
+}
+
+/// How data is moved between the FIFO and memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+    Pio,
+    Dma,
+}
+
+/// What the inserted card implements, from the CMD8, CMD1 and CMD5 probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardKind {
+    /// SD memory only
+    Sd,
+    /// eMMC device, which does not answer the SD CMD8
+    Mmc,
+    /// SDIO functions only
+    Io,
+    /// SDIO functions and memory
+    Combo,
+}
+
+/// A buffer in DMA-safe memory together with its physical address, for
+/// transfers straight into it without a bounce copy.
+pub struct PhysBuf<'a> {
+    pub virt: &'a mut [u8],
+    pub phys: u64,
+}
+
+/// Blocks read one at a time from a single CMD18, see
+/// [`DwMmcHost::block_reader`]. Dropping the reader ends the transfer with
+/// CMD12.
+pub struct BlockReader<'a> {
+    host: &'a mut DwMmcHost,
+    lba: usize,
+    count: usize,
+    done: usize,
+    started: bool,
+    failed: bool,
+    block: [u8; 512],
+    _guard: LockGuard,
+}
+
+impl BlockReader<'_> {
+    /// The next block, or `None` once all blocks have been returned or a
+    /// previous call failed.
+    pub fn next_block(&mut self) -> Option<Result<&[u8; 512], CardError>> {
+        if self.failed || self.done == self.count {
+            return None;
+        }
+        match self.read_next() {
+            Ok(()) => {
+                self.done += 1;
+                Some(Ok(&self.block))
+            }
+            Err(err) => {
+                debug!("block reader at {}: {err:?}", self.lba + self.done);
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> Result<(), CardError> {
+        let blk_sz = self.block.len() as u32;
+        let ops = &self.host.mmc_opt;
+        if !self.started {
+            self.host.check_range(18, self.lba, self.count)?;
+            self.started = true;
+            let addr = self.host.card_address(self.lba);
+            ops.start_read_stream(addr, self.count as u32, blk_sz)?;
+        }
+        ops.read_fifo(&mut self.block, self.done + 1 == self.count)
+    }
+}
+
+impl Drop for BlockReader<'_> {
+    fn drop(&mut self) {
+        if !self.started {
+            return;
+        }
+        if let Err(err) = self.host.mmc_opt.stop_transmission_ops() {
+            debug!("stop block reader: {err:?}");
+        }
+        if self.failed || self.done != self.count {
+            self.host.mmc_opt.recover_single_block(false);
+        }
+    }
+}
+
+/// Reads of block 0 made at each bus width by [`DwMmcHost::diagnose`].
+const DIAGNOSE_READS: usize = 4;
+/// Default for [`DwMmcHost::with_transfer_retries`].
+const DEFAULT_TRANSFER_RETRIES: u8 = 3;
+/// Pause before a block transfer is retried.
+const TRANSFER_RETRY_DELAY_MS: u64 = 1;
+/// Largest count ACMD23 takes, 23 bits.
+const PREERASE_MAX_BLOCKS: usize = 0x7F_FFFF;
+
+/// Driver for one DW MMC controller slot.
+///
+/// Nothing inside serializes access: unless a lock is installed with
+/// [`Self::with_lock`], the host must be owned by a single task.
 pub struct DwMmcHost {
     sdio_base: usize,
     rca: Rca,
@@ -25,32 +334,1912 @@ pub struct DwMmcHost {
     cic: Cic,
     cid: Cid,
     csd: Csd,
+    scr: Option<Scr>,
+    sd_status: Option<SdStatus>,
+    ext_csd: Option<ExtCsd>,
     hard_config: HardConf,
     mmc_opt: MmcOperate,
     delay: Delay,
     info: DwMMC,
+    speed_mode: SpeedMode,
+    card_kind: CardKind,
+    max_bus_width: BusWidth,
+    bus_width: BusWidth,
+    ddr_phase: Option<u8>,
+    use_dma: bool,
+    transfer_mode: TransferMode,
+    pio_reason: Option<&'static str>,
+    idle_retries: u8,
+    current_limit: Option<CurrentLimit>,
+    post_reset_delay_us: u64,
+    source_clock_hz: u32,
+    force_clock_hz: Option<u32>,
+    clock_hz: u32,
+    auto_high_speed: bool,
+    init_error: Option<InitFailure>,
+    init_clock: Option<MicrosClock>,
+    init_mark: u64,
+    init_timings: [u32; InitStep::ALL.len()],
+    lock: Option<&'static dyn CmdLock>,
+    descs: [Cell<IdmacDesc>; IDMAC_DESCS],
+    cmd_pullup: Option<&'static dyn CmdPullup>,
+    last_error: Cell<Option<CardError>>,
+    board_init: Option<fn(usize)>,
+    card_detect_mode: CardDetectMode,
+    write_protect_switch: bool,
+    card_present: Cell<bool>,
+    write_calibration: Option<(usize, MicrosClock)>,
+    skip_cmd_settle: bool,
+    prefer_cmd23: bool,
+    preerase: bool,
+    event_log: bool,
+    events: RefCell<EventLog>,
+    signal_voltage: Option<&'static dyn SignalVoltage>,
+    cid_suspect: bool,
+    csd_suspect: bool,
+    max_blocks_per_command: Option<usize>,
+    transfer_retries: u8,
+    data_timeout_ms: Option<u64>,
+    init_voltage: InitVoltage,
+    measured_write_us: Option<u32>,
 }
 
-impl DwMmcHost {
-    pub const fn new(sdio_base: usize, ticker: &'static dyn Ticker) -> Self {
-        let mmc = MmcOperate::new(sdio_base, ticker);
-        Self {
-            sdio_base: sdio_base,
-            rca: Rca::new(),
-            ocr: Ocr::new(),
-            cic: Cic::new(),
-            cid: Cid::new(),
-            csd: Csd::new(),
-            hard_config: HardConf(0),
-            mmc_opt: mmc,
-            delay: Delay::new(ticker),
-            info: DwMMC::new(),
+impl DwMmcHost {
+    pub const fn new(sdio_base: usize, ticker: &'static dyn Ticker) -> Self {
+        let mmc = MmcOperate::new(sdio_base, ticker);
+        Self {
+            sdio_base: sdio_base,
+            rca: Rca::new(),
+            ocr: Ocr::new(),
+            cic: Cic::new(),
+            cid: Cid::new(),
+            csd: Csd::new(),
+            scr: None,
+            sd_status: None,
+            ext_csd: None,
+            hard_config: HardConf(0),
+            mmc_opt: mmc,
+            delay: Delay::new(ticker),
+            info: DwMMC::new(),
+            speed_mode: SpeedMode::Default,
+            card_kind: CardKind::Sd,
+            max_bus_width: BusWidth::Four,
+            bus_width: BusWidth::Four,
+            ddr_phase: None,
+            use_dma: false,
+            transfer_mode: TransferMode::Pio,
+            pio_reason: None,
+            idle_retries: 3,
+            current_limit: None,
+            post_reset_delay_us: 100,
+            source_clock_hz: 50_000_000,
+            force_clock_hz: None,
+            clock_hz: 0,
+            auto_high_speed: true,
+            init_error: None,
+            init_clock: None,
+            init_mark: 0,
+            init_timings: [0; InitStep::ALL.len()],
+            lock: None,
+            descs: [const { Cell::new(IdmacDesc::new()) }; IDMAC_DESCS],
+            cmd_pullup: None,
+            last_error: Cell::new(None),
+            board_init: None,
+            card_detect_mode: CardDetectMode::Polling,
+            write_protect_switch: true,
+            card_present: Cell::new(false),
+            write_calibration: None,
+            skip_cmd_settle: false,
+            prefer_cmd23: false,
+            preerase: false,
+            event_log: false,
+            events: RefCell::new(EventLog::new()),
+            signal_voltage: None,
+            cid_suspect: false,
+            csd_suspect: false,
+            max_blocks_per_command: None,
+            transfer_retries: DEFAULT_TRANSFER_RETRIES,
+            data_timeout_ms: None,
+            init_voltage: InitVoltage::V33ThenV18,
+            measured_write_us: None,
+        }
+    }
+
+    /// Request IDMAC transfers. `init` falls back to PIO when the controller
+    /// or platform cannot support it, see [`Self::transfer_mode`].
+    ///
+    /// The controller is given plain pointers, so the host and every buffer
+    /// passed to the block calls must be identity mapped, word aligned and
+    /// below 4 GiB. Transfers larger than 128 KiB are split into several
+    /// commands.
+    pub const fn with_dma(mut self, use_dma: bool) -> Self {
+        self.use_dma = use_dma;
+        self
+    }
+
+    /// Cache maintenance of DMA buffers and descriptors, needed when the
+    /// IDMAC is not coherent with the CPU caches.
+    pub const fn with_dma_coherency(mut self, coherency: &'static dyn DmaCoherency) -> Self {
+        self.mmc_opt = self.mmc_opt.with_dma_coherency(coherency);
+        self
+    }
+
+    /// Data bus width selected by `init`. Narrowed to what the controller,
+    /// the card and its SCR allow; SD cards never go to 8-bit. Defaults to
+    /// 4-bit.
+    pub const fn with_bus_width(mut self, width: BusWidth) -> Self {
+        self.bus_width = width;
+        self
+    }
+
+    /// How [`Self::poll_card_event`] learns about card changes. Defaults to
+    /// [`CardDetectMode::Polling`].
+    pub const fn with_card_detect_mode(mut self, mode: CardDetectMode) -> Self {
+        self.card_detect_mode = mode;
+        self
+    }
+
+    /// Whether the slot has a write protect switch wired to `WRTPRT`.
+    /// Without one the pin may float, so writes no longer check it. Defaults
+    /// to true.
+    pub const fn with_write_protect_switch(mut self, present: bool) -> Self {
+        self.write_protect_switch = present;
+        self
+    }
+
+    /// Number of times CMD0 is sent, 1 ms apart, before CMD8. Cards on slowly
+    /// ramping supplies can miss the first one. Defaults to 3.
+    pub const fn with_idle_retries(mut self, retries: u8) -> Self {
+        self.idle_retries = retries;
+        self
+    }
+
+    /// Settle time after the controller reset completes and before the power
+    /// and clock registers are written. Defaults to 100 us.
+    pub const fn with_post_reset_delay_us(mut self, us: u64) -> Self {
+        self.post_reset_delay_us = us;
+        self
+    }
+
+    /// Frequency of the controller input clock `cclk_in`. Defaults to 50 MHz.
+    pub const fn with_source_clock_hz(mut self, hz: u32) -> Self {
+        self.source_clock_hz = hz;
+        self
+    }
+
+    /// Run the whole sequence, identification included, at a fixed clock
+    /// instead of the 400 kHz identification ramp.
+    ///
+    /// Not for real hardware: cards must be identified at 400 kHz or below.
+    /// This is meant for CI against an emulated controller and known cards.
+    pub const fn with_force_clock_hz(mut self, hz: u32) -> Self {
+        self.force_clock_hz = Some(hz);
+        self
+    }
+
+    /// Whether init issues the CMD6 high-speed switch. With `false` the card
+    /// stays in default speed at 25 MHz, which helps tell whether high speed
+    /// is behind data errors on a board. Defaults to `true`.
+    pub const fn with_auto_high_speed(mut self, enable: bool) -> Self {
+        self.auto_high_speed = enable;
+        self
+    }
+
+    /// Idle time inserted before every command, on top of the settle time
+    /// after each one, for cards that corrupt back-to-back responses.
+    /// Defaults to 0.
+    pub const fn with_command_gap_us(mut self, us: u64) -> Self {
+        self.mmc_opt = self.mmc_opt.with_command_gap_us(us);
+        self
+    }
+
+    /// Board card supply in millivolts, checked against the voltage window of
+    /// the card's OCR during init. A mismatch is only logged as a warning.
+    pub const fn with_supply_mv(mut self, mv: u16) -> Self {
+        self.mmc_opt = self.mmc_opt.with_supply_mv(mv);
+        self
+    }
+
+    /// Number of CMD12 retries on a hardware lock error before the controller
+    /// is reset. The delay between retries doubles from 10 us. Defaults to 8.
+    pub const fn with_hle_retries(mut self, retries: u8) -> Self {
+        self.mmc_opt = self.mmc_opt.with_hle_retries(retries);
+        self
+    }
+
+    /// Split [`Self::read_blocks`] and [`Self::write_blocks`] into CMD18 and
+    /// CMD25 transfers of at most `blocks` blocks, each ended with CMD12, for
+    /// cards that get unreliable on longer open-ended transfers. Unlimited
+    /// by default; 0 counts as 1.
+    pub const fn with_max_blocks_per_command(mut self, blocks: usize) -> Self {
+        self.max_blocks_per_command = Some(if blocks == 0 { 1 } else { blocks });
+        self
+    }
+
+    /// Try a block transfer command up to `attempts` times when it fails
+    /// with a [`Transient`] error, sending CMD12 and pausing between tries.
+    /// 3 by default; 0 counts as 1, which disables retrying.
+    pub const fn with_transfer_retries(mut self, attempts: u8) -> Self {
+        self.transfer_retries = if attempts == 0 { 1 } else { attempts };
+        self
+    }
+
+    /// Decode CID and CSD responses that fail the CRC check instead of
+    /// failing init, for cards and controllers known to get the R2 CRC
+    /// wrong. Registers read this way are marked suspect, see
+    /// [`Self::registers_trustworthy`].
+    pub const fn with_r2_crc_quirk(mut self, enable: bool) -> Self {
+        self.mmc_opt = self.mmc_opt.with_r2_crc_quirk(enable);
+        self
+    }
+
+    /// End CMD18 and CMD25 transfers with the controller's auto-stop, which
+    /// sends CMD12 in hardware as the last block goes through, instead of
+    /// the software CMD12. Some IP revisions get auto-stop wrong, so it is
+    /// off by default. Transfers announced with CMD23 need neither, and a
+    /// failed transfer is always stopped by software.
+    pub const fn with_auto_stop(mut self, enable: bool) -> Self {
+        self.mmc_opt = self.mmc_opt.with_auto_stop(enable);
+        self
+    }
+
+    /// Check every response as far as the controller lets software see it,
+    /// instead of trusting its CRC check alone: R2 responses are checked
+    /// against their CRC7 in software, and COM_CRC_ERROR reported in an R1
+    /// or R6 response fails the command with [`CardError::CommandCrc`]. The
+    /// command index and CRC7 of short responses never reach software and
+    /// stay with the controller's check. Off by default.
+    pub const fn with_strict_responses(mut self, enable: bool) -> Self {
+        self.mmc_opt = self.mmc_opt.with_strict(enable);
+        self
+    }
+
+    /// Number of FIFO entries moved each time the receive FIFO is serviced.
+    ///
+    /// Larger batches mean fewer service rounds per block but keep the CPU in
+    /// one round longer; smaller ones return sooner at the cost of more
+    /// rounds. Defaults to the `FIFOTH` watermark read at reset.
+    pub const fn with_fifo_batch(mut self, entries: u32) -> Self {
+        self.mmc_opt = self.mmc_opt.with_fifo_batch(entries);
+        self
+    }
+
+    /// When to wait for the FIFO reset after a data command. Defaults to
+    /// [`FifoResetPolicy::Always`].
+    pub const fn with_fifo_reset_policy(mut self, policy: FifoResetPolicy) -> Self {
+        self.mmc_opt = self.mmc_opt.with_fifo_reset_policy(policy);
+        self
+    }
+
+    /// Board regulator for the 1.8 V signal voltage. With it, `init` runs the
+    /// CMD11 switch for cards that accept 1.8 V, which SDR50 and SDR104
+    /// need, unless [`Self::with_init_voltage`] says otherwise.
+    pub const fn with_signal_voltage(mut self, regulator: &'static dyn SignalVoltage) -> Self {
+        self.signal_voltage = Some(regulator);
+        self
+    }
+
+    /// Keep the last [`EVENT_LOG_LEN`] bytes of key events as text lines,
+    /// init failures and completion, transfer errors, retries and card
+    /// changes, to be read with [`Self::drain_log`] where no `log` backend
+    /// is available. Off by default.
+    pub const fn with_event_log(mut self, enable: bool) -> Self {
+        self.event_log = enable;
+        self
+    }
+
+    /// Move the oldest bytes of the event log into `buf` and return how many
+    /// were moved. Always 0 without [`Self::with_event_log`].
+    pub fn drain_log(&mut self, buf: &mut [u8]) -> usize {
+        self.events.get_mut().drain(buf)
+    }
+
+    /// Signal voltage sequence of `init` for the board's supplies. Defaults
+    /// to [`InitVoltage::V33ThenV18`].
+    pub const fn with_init_voltage(mut self, voltage: InitVoltage) -> Self {
+        self.init_voltage = voltage;
+        self
+    }
+
+    /// Switch the CMD line pull-up around identification, for boards where it
+    /// is not always on.
+    pub const fn with_cmd_pullup(mut self, pullup: &'static dyn CmdPullup) -> Self {
+        self.cmd_pullup = Some(pullup);
+        self
+    }
+
+    /// Board quirk hook, called with the controller base address during init.
+    ///
+    /// It runs once the controller, FIFO and DMA resets have completed and the
+    /// post-reset delay has passed: card power is still off, the card clock
+    /// is not running and every register except `CTRL` and `FIFOTH`, already
+    /// programmed with the FIFO thresholds, still holds its reset value. The
+    /// driver then enables power, starts the 400 kHz clock and sends CMD0.
+    pub const fn with_board_init(mut self, hook: fn(usize)) -> Self {
+        self.board_init = Some(hook);
+        self
+    }
+
+    /// Time every init step with `clock`, see [`Self::init_timings`].
+    pub const fn with_init_timing(mut self, clock: MicrosClock) -> Self {
+        self.init_clock = Some(clock);
+        self
+    }
+
+    /// Skip the 100 us settle time after each command, which dominates small
+    /// transfers. Commands are still submitted one at a time: the controller
+    /// has no command queue.
+    ///
+    /// Safe on every IP revision as far as the controller goes: the next
+    /// command is only loaded once `start_cmd` has cleared, the handshake
+    /// the databook asks for. The settle time is extra margin for cards and
+    /// boards that need it, so it stays on by default.
+    pub const fn with_skip_cmd_settle(mut self, enable: bool) -> Self {
+        self.skip_cmd_settle = enable;
+        self
+    }
+
+    /// Measure the card programming time at the end of `init` by writing
+    /// block `lba` back with its own contents, timed with `clock`. Write
+    /// programming then times out after ten times the measured time, but no
+    /// sooner than 250 ms, see [`Self::measured_write_time_us`].
+    ///
+    /// Pick a block nothing else writes during init; a power loss during the
+    /// calibration write can still corrupt it.
+    pub const fn with_write_calibration(mut self, lba: usize, clock: MicrosClock) -> Self {
+        self.write_calibration = Some((lba, clock));
+        self
+    }
+
+    /// Serialize block transfers through `lock`, for sharing one host
+    /// between tasks of a preemptive RTOS.
+    ///
+    /// Every command takes the lock, and so does every call that touches
+    /// the data path registers. Calls made of several commands, block
+    /// transfers above all, hold it from the first command to the end of
+    /// the data phase, so the lock is taken again by the task already
+    /// holding it and must allow that, like a recursive mutex. Init still
+    /// has to finish before the host is shared.
+    pub const fn with_lock(mut self, lock: &'static dyn CmdLock) -> Self {
+        self.lock = Some(lock);
+        self.mmc_opt = self.mmc_opt.with_lock(lock);
+        self
+    }
+
+    /// The error behind the last failed `read_block` or `write_block`, which
+    /// only return the coarser `DeviceError`.
+    pub fn last_error(&self) -> Option<CardError> {
+        self.last_error.get()
+    }
+
+    /// The step, error and controller registers of the last failed init.
+    /// Cleared when init starts again.
+    pub fn last_init_error(&self) -> Option<InitFailure> {
+        self.init_error
+    }
+
+    /// Microseconds spent in each step of the last init, including the fixed
+    /// delays before it. Steps run more than once, CMD0 and the clock setup,
+    /// are summed. All zero unless enabled with [`Self::with_init_timing`].
+    pub fn init_timings(&self) -> [(InitStep, u32); InitStep::ALL.len()] {
+        InitStep::ALL.map(|step| (step, self.init_timings[step as usize]))
+    }
+
+    /// Programming time of the calibration write from
+    /// [`Self::with_write_calibration`], `None` without calibration or if it
+    /// failed.
+    pub fn measured_write_time_us(&self) -> Option<u32> {
+        self.measured_write_us
+    }
+
+    /// The active transfer mode and, if DMA was requested but PIO is in use,
+    /// the reason for the fallback.
+    pub fn transfer_mode(&self) -> (TransferMode, Option<&'static str>) {
+        (self.transfer_mode, self.pio_reason)
+    }
+
+    /// Identify the inserted card without configuring it for I/O.
+    ///
+    /// Runs CMD0, CMD8, ACMD41, CMD2, CMD3 and CMD9, then leaves the card
+    /// deselected in stand-by state. Call [`Self::configure_for_io`] to finish
+    /// the setup if the card is going to be used.
+    pub fn identify_only(&mut self) -> Result<CardSummary, CardError> {
+        self.init_controller()?;
+        self.identify()?;
+        Ok(self.summary())
+    }
+
+    /// Select the identified card, read its SCR (Ext CSD on eMMC), switch it
+    /// to high speed unless disabled with [`Self::with_auto_high_speed`] and
+    /// to the bus width from [`Self::with_bus_width`], and raise the card
+    /// clock.
+    pub fn configure_for_io(&mut self) -> Result<(), CardError> {
+        self.step(InitStep::SelectCard, self.mmc_opt.sel_card(self.rca))?;
+        self.speed_mode = SpeedMode::Default;
+        if self.card_kind == CardKind::Mmc {
+            let ext = self.step(InitStep::ReadExtCsd, self.mmc_opt.read_ext_csd())?;
+            self.ext_csd = Some(ext);
+            if self.auto_high_speed && ext.high_speed_52() {
+                let ret = self
+                    .mmc_opt
+                    .mmc_switch(self.rca, sd_reg::EXT_CSD_HS_TIMING, 1);
+                self.step(InitStep::SwitchFunction, ret)?;
+                self.speed_mode = SpeedMode::HighSpeed;
+            }
+        } else {
+            // Without an SCR, CMD12 ends multi-block transfers and the bus
+            // width is picked from the 1 and 4-bit widths every card has.
+            self.scr = match self.mmc_opt.read_scr(self.rca) {
+                Ok(scr) => Some(scr),
+                Err(err) => {
+                    warn!("SCR read failed, using defaults: {err}");
+                    self.event(format_args!("SCR read failed: {err}"));
+                    None
+                }
+            };
+            if self.auto_high_speed {
+                match self.mmc_opt.switch_high_speed() {
+                    Err(CardError::Unsupported) => {
+                        info!("card has no CMD6, stay at default speed")
+                    }
+                    Err(CardError::UnsupportedSpeedMode) => info!("stay at default speed"),
+                    ret => {
+                        self.step(InitStep::SwitchFunction, ret)?;
+                        self.speed_mode = SpeedMode::HighSpeed;
+                    }
+                }
+            }
+        }
+        let width = [
+            self.bus_width,
+            BusWidth::Eight,
+            BusWidth::Four,
+            BusWidth::One,
+        ]
+        .into_iter()
+        .filter(|width| *width as u8 <= self.bus_width as u8)
+        .find(|width| self.check_bus_width(*width).is_ok())
+        .unwrap_or(BusWidth::One);
+        let ret = self.switch_bus(width);
+        self.step(InitStep::SetBusWidth, ret)?;
+        let hz = match self.speed_mode {
+            SpeedMode::HighSpeed => HIGH_SPEED_CLOCK_HZ,
+            _ => DEFAULT_CLOCK_HZ,
+        };
+        let ret = self.apply_clock(hz);
+        self.step(InitStep::SetClock, ret)?;
+        write_reg::<u32>(
+            self.sdio_base,
+            REG_IDINTEN,
+            (DmaIntEn::ri | DmaIntEn::ti).bits(),
+        );
+        Ok(())
+    }
+
+    /// Switch the card and the controller to `mode`.
+    ///
+    /// UHS modes also commit the limit set with [`Self::set_current_limit`] in
+    /// the same CMD6, so the card never runs SDR104 above the board budget.
+    ///
+    /// DDR50 needs the controller hold register (all commands are already
+    /// issued through it), runs on the 4-bit bus, sets the DDR bit in
+    /// `REG_UHS` and applies the phase set with [`Self::set_ddr_phase`].
+    pub fn set_speed_mode(&mut self, mode: SpeedMode) -> Result<(), CardError> {
+        let ddr = mode == SpeedMode::Ddr50;
+        if ddr && self.hard_config.0 & HardConfig::impl_hold_reg.bits() == 0 {
+            return Err(CardError::UnsupportedSpeedMode);
+        }
+        let mut arg = Cmd6Arg::switch().group(1, mode.function());
+        if let (true, Some(limit)) = (mode.is_uhs(), self.current_limit) {
+            arg = arg.group(4, limit.function());
+        }
+        self.mmc_opt.function_switch(arg)?;
+        if ddr {
+            write_reg::<u32>(self.sdio_base, REG_CTYPE, 1);
+        }
+        self.mmc_opt.set_ddr(ddr);
+        if let (true, Some(phase)) = (ddr, self.ddr_phase) {
+            self.mmc_opt.set_sample_phase(phase);
+        }
+        self.speed_mode = mode;
+        Ok(())
+    }
+
+    pub fn speed_mode(&self) -> SpeedMode {
+        self.speed_mode
+    }
+
+    /// Announce the length of CMD18 and CMD25 transfers with CMD23 instead
+    /// of ending them with CMD12, on cards that accept it: eMMC, and SD
+    /// cards whose SCR has the CMD23 support bit. Off by default.
+    pub const fn with_cmd23(mut self, enable: bool) -> Self {
+        self.prefer_cmd23 = enable;
+        self
+    }
+
+    /// Whether the card accepts SET_BLOCK_COUNT (CMD23), from the SCR.
+    /// `false` until the SCR has been read.
+    pub fn supports_cmd23(&self) -> bool {
+        self.scr.is_some_and(|scr| scr.cmd23_supported())
+    }
+
+    /// Switch the card and controller to 1.8 V signalling with CMD11.
+    ///
+    /// Only valid right after ACMD41 in identification, which `init` does by
+    /// itself when [`Self::with_signal_voltage`] is set. Fails with
+    /// [`CardError::Unsupported`] without a regulator or when the OCR does
+    /// not report S18A, and with [`CardError::VoltageSwitch`] when the
+    /// sequence breaks off; the card must then be power cycled.
+    pub fn switch_to_1v8(&mut self) -> Result<(), CardError> {
+        let Some(regulator) = self.signal_voltage else {
+            return Err(CardError::Unsupported);
+        };
+        if !self.ocr.v18_allowed() {
+            return Err(CardError::Unsupported);
+        }
+        self.mmc_opt.switch_voltage(|| regulator.set_1v8())
+    }
+
+    /// Widest data bus the controller was synthesized for. HCON does not
+    /// record it, so init finds it by writing every width bit of `CTYPE` and
+    /// reading back which ones stick.
+    pub fn max_bus_width(&self) -> BusWidth {
+        self.max_bus_width
+    }
+
+    /// Switch the card and controller data bus to `width`. Fails with
+    /// [`CardError::Unsupported`] for widths above [`Self::max_bus_width`],
+    /// for 4-bit when the SCR does not list it and for 8-bit on SD cards,
+    /// which only eMMC has.
+    pub fn set_bus_width(&mut self, width: BusWidth) -> Result<(), CardError> {
+        self.check_bus_width(width)?;
+        self.switch_bus(width)
+    }
+
+    /// ACMD6 on SD cards, the Ext CSD BUS_WIDTH byte on eMMC.
+    fn switch_bus(&self, width: BusWidth) -> Result<(), CardError> {
+        match self.card_kind {
+            CardKind::Mmc => self.mmc_opt.set_mmc_bus(self.rca, width),
+            _ => self.mmc_opt.set_bus(self.rca, width),
+        }
+    }
+
+    fn check_bus_width(&self, width: BusWidth) -> Result<(), CardError> {
+        let mmc = self.card_kind == CardKind::Mmc;
+        let supported = match width {
+            BusWidth::One => true,
+            BusWidth::Four => mmc || self.scr.is_none_or(|scr| scr.bus_width_four()),
+            BusWidth::Eight => mmc,
+            BusWidth::Unknown => false,
+        };
+        if !supported || width as u8 > self.max_bus_width as u8 {
+            debug!("{width:?} bus not supported");
+            return Err(CardError::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Kind of the identified card. I/O-only cards fail init with
+    /// [`CardError::Unsupported`] at [`InitStep::IoSendOpCond`], but are
+    /// still reported here.
+    pub fn card_kind(&self) -> CardKind {
+        self.card_kind
+    }
+
+    /// Card capacity in bytes decoded from the CSD, for CSD version 1.0
+    /// (C_SIZE, C_SIZE_MULT and READ_BL_LEN) as well as the fixed 512 KiB
+    /// units of versions 2.0 and 3.0. 0 until `init` has identified a card.
+    pub fn capacity_bytes(&self) -> u64 {
+        if !self.rca.is_valid() {
+            return 0;
+        }
+        match self.ext_csd.map(|ext| ext.sec_count()) {
+            Some(sectors) if sectors != 0 => u64::from(sectors) * BlockSize::Lb512 as u64,
+            _ => self.csd.card_size(),
+        }
+    }
+
+    /// Number of 512-byte blocks on the card, one past the last valid LBA.
+    /// 0 until `init` has identified a card.
+    pub fn block_count(&self) -> u64 {
+        self.capacity_bytes() / BlockSize::Lb512 as u64
+    }
+
+    /// Cap the card peak current, CMD6 function group 4. Only meaningful for
+    /// SDR50, SDR104 and DDR50; cards start at 200 mA.
+    pub fn set_current_limit(&mut self, limit: CurrentLimit) -> Result<(), CardError> {
+        self.mmc_opt
+            .function_switch(Cmd6Arg::switch().group(4, limit.function()))?;
+        self.current_limit = Some(limit);
+        Ok(())
+    }
+
+    /// Select the card output driver strength. A weaker driver helps boards
+    /// with reflections or overshoot in UHS modes.
+    pub fn set_driver_strength(&mut self, strength: DriverStrength) -> Result<(), CardError> {
+        self.mmc_opt
+            .function_switch(Cmd6Arg::switch().group(3, strength.function()))?;
+        Ok(())
+    }
+
+    /// Send ACMD23 with the block count before each CMD25 of
+    /// [`Self::write_blocks`], letting an SD card erase the blocks ahead of
+    /// the data. Single blocks written with CMD24 are not pre-erased, nor is
+    /// eMMC, which has no ACMD23. Off by default.
+    pub fn set_preerase(&mut self, enable: bool) {
+        self.preerase = enable;
+    }
+
+    /// Set the sample phase used in DDR50 mode, `0..=0x7F`.
+    pub fn set_ddr_phase(&mut self, phase: u8) {
+        let phase = phase.min(CLK_SMPL_PHASE_MAX);
+        self.ddr_phase = Some(phase);
+        if self.speed_mode == SpeedMode::Ddr50 {
+            self.mmc_opt.set_sample_phase(phase);
+        }
+    }
+
+    /// Sweep every sample phase in DDR50 mode, reading block 0 at each step,
+    /// and keep the centre of the widest passing window.
+    pub fn ddr_phase_sweep(&mut self) -> Result<u8, CardError> {
+        if self.speed_mode != SpeedMode::Ddr50 {
+            return Err(CardError::UnsupportedSpeedMode);
+        }
+        let mut buf = [0u8; 512];
+        let (mut start, mut len) = (0u8, 0u8);
+        let (mut best_start, mut best_len) = (0u8, 0u8);
+        for phase in 0..=CLK_SMPL_PHASE_MAX {
+            self.mmc_opt.set_sample_phase(phase);
+            match self.read_single(0, &mut buf) {
+                Ok(()) => {
+                    if len == 0 {
+                        start = phase;
+                    }
+                    len += 1;
+                    if len > best_len {
+                        (best_start, best_len) = (start, len);
+                    }
+                }
+                // A start bit error is the usual sign of a wrong phase.
+                Err(err) => {
+                    trace!("phase {phase}: {err}");
+                    len = 0;
+                }
+            }
+        }
+        if best_len == 0 {
+            if let Some(phase) = self.ddr_phase {
+                self.mmc_opt.set_sample_phase(phase);
+            }
+            return Err(CardError::TuningFailed);
+        }
+        let phase = best_start + best_len / 2;
+        debug!(
+            "ddr phase window {best_start}..{}, use {phase}",
+            best_start + best_len
+        );
+        self.set_ddr_phase(phase);
+        Ok(phase)
+    }
+
+    /// Set the sample phase, `0..=0x7F`, directly in the delay line,
+    /// whatever the speed mode. For mapping the valid window by hand; the
+    /// value is not kept across speed mode switches.
+    pub fn set_sample_phase(&mut self, step: u8) {
+        self.mmc_opt.set_sample_phase(step.min(CLK_SMPL_PHASE_MAX));
+    }
+
+    /// The sample phase currently programmed in the delay line.
+    pub fn get_sample_phase(&self) -> u8 {
+        self.mmc_opt.sample_phase()
+    }
+
+    /// Try every sample phase and return a bitmap with bit `n` set when phase
+    /// `n` passed. SDR50 and SDR104 read the CMD19 tuning block and compare
+    /// it with the pattern, other modes read block 0. The phase in use before
+    /// the scan is restored.
+    pub fn sample_window_scan(&mut self) -> u128 {
+        let saved = self.mmc_opt.sample_phase();
+        let tuning = matches!(self.speed_mode, SpeedMode::Sdr50 | SpeedMode::Sdr104);
+        let mut buf = [0u8; 512];
+        let mut window = 0u128;
+        for phase in 0..=CLK_SMPL_PHASE_MAX {
+            self.mmc_opt.set_sample_phase(phase);
+            let pass = if tuning {
+                self.mmc_opt.tuning_block_matches().unwrap_or(false)
+            } else {
+                self.read_single(0, &mut buf).is_ok()
+            };
+            if pass {
+                window |= 1 << phase;
+            }
+        }
+        self.mmc_opt.set_sample_phase(saved);
+        debug!("sample window {window:#034x}");
+        window
+    }
+
+    /// Read `count` blocks from `start_lba` with CMD18 and check each one
+    /// against its CRC32 in `crcs`.
+    ///
+    /// The blocks stream through a buffer of [`VERIFY_CHUNK_BLOCKS`] blocks.
+    /// Only 512-byte blocks are supported. Returns the first LBA whose CRC
+    /// does not match, or that has no entry in `crcs`, or `None` when the
+    /// whole range verified.
+    pub fn read_and_verify_manifest(
+        &mut self,
+        start_lba: usize,
+        count: usize,
+        crcs: &[u32],
+    ) -> Result<Option<usize>, CardError> {
+        const BLK_SZ: usize = 512;
+        if self.block_size() as usize != BLK_SZ {
+            return Err(CardError::BufferLength);
+        }
+        let _guard = LockGuard::new(self.lock);
+        let listed = count.min(crcs.len());
+        let mut buf = [0u8; VERIFY_CHUNK_BLOCKS * BLK_SZ];
+        for (i, expected) in crcs[..listed].chunks(VERIFY_CHUNK_BLOCKS).enumerate() {
+            let lba = start_lba + i * VERIFY_CHUNK_BLOCKS;
+            let blocks = &mut buf[..expected.len() * BLK_SZ];
+            self.read_blocks(lba, blocks)?;
+            let mut checks = blocks.chunks_exact(BLK_SZ).zip(expected);
+            if let Some(j) = checks.position(|(block, &crc)| crc::crc32(block) != crc) {
+                debug!("block {} crc mismatch", lba + j);
+                return Ok(Some(lba + j));
+            }
+        }
+        Ok((listed < count).then_some(start_lba + listed))
+    }
+
+    /// Write `data` from `start_lba` with [`Self::write_blocks`], then read
+    /// the range back with CMD18 and compare it block by block.
+    ///
+    /// The readback streams through a buffer of [`VERIFY_CHUNK_BLOCKS`]
+    /// blocks, so no second copy of `data` is held. Only 512-byte blocks are
+    /// supported. Returns the first LBA that reads back different, or `None`
+    /// when the whole range verified.
+    pub fn write_blocks_verified(
+        &mut self,
+        start_lba: usize,
+        data: &[u8],
+    ) -> Result<Option<usize>, CardError> {
+        const BLK_SZ: usize = 512;
+        if self.block_size() as usize != BLK_SZ {
+            return Err(CardError::BufferLength);
+        }
+        self.write_blocks(start_lba, data)?;
+        let mut buf = [0u8; VERIFY_CHUNK_BLOCKS * BLK_SZ];
+        for (i, expected) in data.chunks(buf.len()).enumerate() {
+            let lba = start_lba + i * VERIFY_CHUNK_BLOCKS;
+            let readback = &mut buf[..expected.len()];
+            self.read_blocks(lba, readback)?;
+            let mut blocks = readback
+                .chunks_exact(BLK_SZ)
+                .zip(expected.chunks_exact(BLK_SZ));
+            if let Some(j) = blocks.position(|(got, want)| got != want) {
+                debug!("block {} mismatch after write", lba + j);
+                return Ok(Some(lba + j));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read `buf.len() / 512` blocks from `lba` with one CMD18, ended by
+    /// CMD12 once the controller interrupts are cleared or preceded by CMD23
+    /// with [`Self::with_cmd23`]. A single block goes through CMD17 instead.
+    pub fn read_blocks(&mut self, lba: usize, buf: &mut [u8]) -> Result<(), CardError> {
+        self.read_blocks_reporting(lba, buf, None)
+    }
+
+    /// [`Self::read_blocks`], calling `progress` with the bytes read so far
+    /// and the total as the transfer goes: after each FIFO burst, or after
+    /// each [`Self::max_transfer`] sized command with the IDMAC. A retried
+    /// command reports its bytes again.
+    pub fn read_blocks_with_progress(
+        &mut self,
+        lba: usize,
+        buf: &mut [u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), CardError> {
+        self.read_blocks_reporting(lba, buf, Some(progress))
+    }
+
+    fn read_blocks_reporting(
+        &mut self,
+        lba: usize,
+        buf: &mut [u8],
+        mut progress: Option<&mut dyn FnMut(usize, usize)>,
+    ) -> Result<(), CardError> {
+        let blk_sz = self.block_size() as u32;
+        if buf.is_empty() || !buf.len().is_multiple_of(blk_sz as usize) {
+            return Err(CardError::BufferLength);
+        }
+        let _guard = LockGuard::new(self.lock);
+        if buf.len() == blk_sz as usize {
+            self.retry_transient(|| self.read_single(lba, buf))?;
+            if let Some(report) = progress.as_mut() {
+                report(buf.len(), buf.len());
+            }
+            return Ok(());
+        }
+        self.check_range(18, lba, buf.len() / blk_sz as usize)?;
+        trace!(
+            "read blocks, address: {}, count: {}",
+            lba,
+            buf.len() / blk_sz as usize
+        );
+        self.read_chunked(lba, buf, progress)
+    }
+
+    /// Write `data.len() / 512` blocks from `lba` with one CMD25 ended by
+    /// CMD12, or CMD24 for a single block, and return once the card has
+    /// finished programming.
+    pub fn write_blocks(&mut self, lba: usize, data: &[u8]) -> Result<(), CardError> {
+        self.write_blocks_reporting(lba, data, None)
+    }
+
+    /// [`Self::write_blocks`], calling `progress` with the bytes written so
+    /// far and the total, like [`Self::read_blocks_with_progress`].
+    pub fn write_blocks_with_progress(
+        &mut self,
+        lba: usize,
+        data: &[u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), CardError> {
+        self.write_blocks_reporting(lba, data, Some(progress))
+    }
+
+    fn write_blocks_reporting(
+        &mut self,
+        lba: usize,
+        data: &[u8],
+        mut progress: Option<&mut dyn FnMut(usize, usize)>,
+    ) -> Result<(), CardError> {
+        let blk_sz = self.block_size() as u32;
+        if data.is_empty() || !data.len().is_multiple_of(blk_sz as usize) {
+            return Err(CardError::BufferLength);
+        }
+        self.check_writable()?;
+        let _guard = LockGuard::new(self.lock);
+        if data.len() == blk_sz as usize {
+            self.retry_transient(|| self.write_single(lba, data))?;
+            if let Some(report) = progress.as_mut() {
+                report(data.len(), data.len());
+            }
+        } else {
+            self.check_range(25, lba, data.len() / blk_sz as usize)?;
+            trace!(
+                "write blocks, address: {}, count: {}",
+                lba,
+                data.len() / blk_sz as usize
+            );
+            let chunk = self.max_transfer();
+            let reporting = progress.is_some();
+            for (i, part) in data.chunks(chunk).enumerate() {
+                let lba = lba + i * chunk / blk_sz as usize;
+                let done = i * chunk;
+                let mut report = |n: usize| {
+                    if let Some(progress) = progress.as_mut() {
+                        progress(done + n, data.len());
+                    }
+                };
+                let mut report = reporting.then_some(&mut report as &mut dyn FnMut(usize));
+                let blocks = part.len() / blk_sz as usize;
+                let predefined = self.use_cmd23(blocks);
+                let preerase = self.preerase
+                    && matches!(self.card_kind, CardKind::Sd | CardKind::Combo)
+                    && blocks <= PREERASE_MAX_BLOCKS;
+                self.retry_transient(|| {
+                    if preerase {
+                        self.mmc_opt
+                            .set_wr_blk_erase_count(self.rca, blocks as u32)?;
+                    }
+                    self.mmc_opt.write_multiple_block(
+                        self.card_address(lba),
+                        part,
+                        blk_sz,
+                        self.dma_descs(),
+                        predefined,
+                        report.as_deref_mut(),
+                    )
+                })?;
+            }
+            self.mmc_opt
+                .wait_programmed(self.rca, CardError::DataTransferTimeout)?;
+        }
+        Ok(())
+    }
+
+    /// Read `count` blocks from `start_lba` lazily through one CMD18, for
+    /// consumers that handle one sector at a time. The transfer always uses
+    /// the FIFO, and nothing is sent before the first
+    /// [`BlockReader::next_block`].
+    pub fn block_reader(&mut self, start_lba: usize, count: usize) -> BlockReader<'_> {
+        let guard = LockGuard::new(self.lock);
+        BlockReader {
+            host: self,
+            lba: start_lba,
+            count,
+            done: 0,
+            started: false,
+            failed: false,
+            block: [0; 512],
+            _guard: guard,
+        }
+    }
+
+    /// Start a fresh CMD18 at `next_lba` and read `buf.len() / 512` blocks.
+    ///
+    /// Meant for recovering a large read that failed midway: pass the LBA of
+    /// the first block that was not transferred instead of starting over.
+    pub fn resume_read(&mut self, next_lba: usize, buf: &mut [u8]) -> Result<(), CardError> {
+        let blk_sz = self.block_size() as u32;
+        if buf.is_empty() || !buf.len().is_multiple_of(blk_sz as usize) {
+            return Err(CardError::BufferLength);
+        }
+        self.check_range(18, next_lba, buf.len() / blk_sz as usize)?;
+        trace!("resume read, address: {}", next_lba);
+        let _guard = LockGuard::new(self.lock);
+        self.read_chunked(next_lba, buf, None)
+    }
+
+    /// CMD18 reads of `buf` from `lba`, one per [`Self::max_transfer`] bytes.
+    fn read_chunked(
+        &self,
+        lba: usize,
+        buf: &mut [u8],
+        mut progress: Option<&mut dyn FnMut(usize, usize)>,
+    ) -> Result<(), CardError> {
+        let blk_sz = self.block_size() as u32;
+        let chunk = self.max_transfer();
+        let (total, reporting) = (buf.len(), progress.is_some());
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            let lba = lba + i * chunk / blk_sz as usize;
+            let done = i * chunk;
+            let mut report = |n: usize| {
+                if let Some(progress) = progress.as_mut() {
+                    progress(done + n, total);
+                }
+            };
+            let mut report = reporting.then_some(&mut report as &mut dyn FnMut(usize));
+            let predefined = self.use_cmd23(part.len() / blk_sz as usize);
+            self.retry_transient(|| {
+                self.mmc_opt.read_multiple_block(
+                    self.card_address(lba),
+                    part,
+                    blk_sz,
+                    self.dma_descs(),
+                    predefined,
+                    report.as_deref_mut(),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether a transfer of `blocks` blocks is announced with CMD23, see
+    /// [`Self::with_cmd23`]. eMMC only takes a 16-bit count.
+    fn use_cmd23(&self, blocks: usize) -> bool {
+        self.prefer_cmd23
+            && match self.card_kind {
+                CardKind::Mmc => blocks <= 0xFFFF,
+                _ => self.supports_cmd23(),
+            }
+    }
+
+    /// Descriptors for the data phase when the IDMAC is in use.
+    fn dma_descs(&self) -> Option<&[Cell<IdmacDesc>]> {
+        match self.transfer_mode {
+            TransferMode::Dma => Some(&self.descs),
+            TransferMode::Pio => None,
+        }
+    }
+
+    /// Most bytes moved by one data command: what the descriptor list covers
+    /// with DMA, unbounded with PIO, and at most
+    /// [`Self::with_max_blocks_per_command`] blocks.
+    fn max_transfer(&self) -> usize {
+        let max = match self.transfer_mode {
+            TransferMode::Dma => IDMAC_DESCS * IDMAC_DESC_BUF_LEN as usize,
+            TransferMode::Pio => usize::MAX,
+        };
+        match self.max_blocks_per_command {
+            Some(blocks) => max.min(blocks.saturating_mul(self.block_size() as usize)),
+            None => max,
+        }
+    }
+
+    /// Read block `lba` with the IDMAC directly into `buf`, which must be
+    /// exactly one block, word aligned and below 4 GiB.
+    ///
+    /// The IDMAC finds its descriptors inside the host, so the host must live
+    /// in identity-mapped memory. Cache maintenance of `buf` is up to the
+    /// caller.
+    pub fn read_block_dma(&mut self, lba: usize, buf: PhysBuf) -> Result<(), CardError> {
+        if self.hard_config.0 & HardConfig::dma_interface.bits() != 0 {
+            return Err(CardError::Unsupported);
+        }
+        let blk_sz = self.block_size() as u32;
+        if buf.virt.len() != blk_sz as usize {
+            return Err(CardError::BufferLength);
+        }
+        let phys = u32::try_from(buf.phys)
+            .ok()
+            .filter(|phys| phys % 4 == 0)
+            .ok_or(CardError::DmaAddress)?;
+        self.check_range(17, lba, 1)?;
+        let _guard = LockGuard::new(self.lock);
+        trace!("read block dma, address: {}", lba);
+        let cmd = read_single_block(self.card_address(lba));
+        let ret = self
+            .mmc_opt
+            .transfer_dma(cmd, &self.descs, phys, blk_sz, blk_sz, false);
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.mmc_opt.recover_single_block(true);
+        }
+        ret
+    }
+
+    /// Zero `count` blocks from `start_lba`.
+    ///
+    /// Uses a single erase when the SCR says erased blocks read as zero,
+    /// which is much faster for large ranges. The erase may take as long as
+    /// the SD Status allows for the AUs it covers, once [`Self::sd_status`]
+    /// has been read, and 250 ms per block otherwise. Without erase, or before
+    /// the SCR has been read, zeros are written with
+    /// [`Self::write_blocks`], [`VERIFY_CHUNK_BLOCKS`] at a time.
+    pub fn write_zeroes(&mut self, start_lba: usize, count: usize) -> Result<(), CardError> {
+        if count == 0 {
+            return Ok(());
+        }
+        self.check_writable()?;
+        self.check_range(32, start_lba, count)?;
+        let _guard = LockGuard::new(self.lock);
+        if self.scr.is_some_and(|scr| scr.data_stat_after_erase() == 0) {
+            trace!("erase blocks {}..{}", start_lba, start_lba + count);
+            let timeout_ms = self
+                .sd_status
+                .and_then(|status| status.erase_timeout_ms(count as u64))
+                .unwrap_or(ERASE_BLOCK_TIMEOUT_MS * count as u64);
+            let start = self.card_address(start_lba);
+            let end = self.card_address(start_lba + count - 1);
+            return self.mmc_opt.erase(start, end, timeout_ms);
+        }
+        let zeros = [0u8; VERIFY_CHUNK_BLOCKS * 512];
+        let end = start_lba + count;
+        for lba in (start_lba..end).step_by(VERIFY_CHUNK_BLOCKS) {
+            let blocks = (end - lba).min(VERIFY_CHUNK_BLOCKS);
+            self.write_blocks(lba, &zeros[..blocks * 512])?;
+        }
+        Ok(())
+    }
+
+    /// Interrupt a long eMMC erase or programming operation with High
+    /// Priority Interrupt so that a read can go through, and wait until the
+    /// device is back in transfer state.
+    ///
+    /// Needs HPI to be supported and enabled in the Ext CSD, otherwise fails
+    /// with [`CardError::Unsupported`].
+    pub fn hpi(&mut self) -> Result<(), CardError> {
+        let ext = self.ext_csd.ok_or(CardError::Unsupported)?;
+        if !ext.hpi_supported() || !ext.hpi_enabled() {
+            return Err(CardError::Unsupported);
+        }
+        let millis = ext.out_of_interrupt_time_ms().max(10);
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt.hpi(self.rca, ext.hpi_uses_cmd12(), millis)
+    }
+
+    /// Make data written so far durable: wait until the card has finished
+    /// programming and, on eMMC with the cache turned on, flush the cache
+    /// with FLUSH_CACHE. A card that reports ready may still hold the data
+    /// only in its cache.
+    pub fn flush(&mut self) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt
+            .wait_programmed(self.rca, Timeout::WaitTranState.into())?;
+        self.flush_cache()
+    }
+
+    /// Flush the eMMC device cache. A no-op for SD cards and for devices
+    /// without a cache or with the cache turned off.
+    pub fn flush_cache(&mut self) -> Result<(), CardError> {
+        match self.ext_csd {
+            Some(ext) if ext.cache_size_kib() != 0 && ext.cache_enabled() => {
+                trace!("flush eMMC cache");
+                self.mmc_opt
+                    .mmc_switch(self.rca, sd_reg::EXT_CSD_FLUSH_CACHE, 1)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Turn the eMMC device cache on or off through Ext CSD CACHE_CTRL.
+    ///
+    /// With the cache on, small writes complete much faster but are only
+    /// durable after [`Self::flush`]; the cache is flushed before it is
+    /// turned off. A no-op for SD cards, [`CardError::Unsupported`] for
+    /// eMMC devices without a cache.
+    pub fn set_cache_enabled(&mut self, enabled: bool) -> Result<(), CardError> {
+        let Some(ext) = self.ext_csd.as_mut() else {
+            return Ok(());
+        };
+        if ext.cache_size_kib() == 0 {
+            return Err(CardError::Unsupported);
+        }
+        if ext.cache_enabled() == enabled {
+            return Ok(());
+        }
+        let _guard = LockGuard::new(self.lock);
+        if !enabled {
+            self.mmc_opt
+                .mmc_switch(self.rca, sd_reg::EXT_CSD_FLUSH_CACHE, 1)?;
+        }
+        self.mmc_opt
+            .mmc_switch(self.rca, sd_reg::EXT_CSD_CACHE_CTRL, enabled as u8)?;
+        ext.set_cache_enabled(enabled);
+        Ok(())
+    }
+
+    /// Size of the eMMC device cache in bytes, `None` for SD cards and for
+    /// devices without a cache.
+    pub fn cache_size_bytes(&self) -> Option<u64> {
+        self.ext_csd
+            .map(|ext| u64::from(ext.cache_size_kib()) * 1024)
+            .filter(|&size| size != 0)
+    }
+
+    /// Pulse the RST_n line to hardware-reset an eMMC device.
+    ///
+    /// The line is held low for at least 1 us and the card then gets 300 us
+    /// before the next command. The card is back in pre-idle state afterwards,
+    /// so run `init` again. Fails with [`CardError::Unsupported`] when the
+    /// controller has no RST_n output: the register resets to 1 when present.
+    pub fn hardware_reset_card(&mut self) -> Result<(), CardError> {
+        if read_reg::<u32>(self.sdio_base, REG_RSTN) & 1 == 0 {
+            return Err(CardError::Unsupported);
+        }
+        write_reg::<u32>(self.sdio_base, REG_RSTN, 0);
+        self.delay.spin_micros(2);
+        write_reg::<u32>(self.sdio_base, REG_RSTN, 1);
+        self.delay.spin_micros(300);
+        Ok(())
+    }
+
+    /// Write-protect the group containing `lba` (CMD28). Group size is given
+    /// by the CSD; SDHC and SDXC cards do not support group protection and
+    /// fail with [`CardError::Unsupported`].
+    pub fn set_write_protect(&mut self, lba: usize) -> Result<(), CardError> {
+        self.check_wp_groups()?;
+        self.check_range(28, lba, 1)?;
+        self.mmc_opt.write_protect(self.card_address(lba), true)
+    }
+
+    /// Clear the write protection of the group containing `lba` (CMD29).
+    pub fn clear_write_protect(&mut self, lba: usize) -> Result<(), CardError> {
+        self.check_wp_groups()?;
+        self.check_range(29, lba, 1)?;
+        self.mmc_opt.write_protect(self.card_address(lba), false)
+    }
+
+    /// Protection bits of the 32 groups starting at the one containing `lba`
+    /// (CMD30). Bit 0 is the addressed group.
+    pub fn query_write_protect(&mut self, lba: usize) -> Result<u32, CardError> {
+        self.check_wp_groups()?;
+        self.check_range(30, lba, 1)?;
+        self.mmc_opt.send_write_protect(self.card_address(lba))
+    }
+
+    /// Reject group write protection on cards without it: SD cards only have
+    /// it as SDSC, and only when the CSD sets WP_GRP_ENABLE.
+    fn check_wp_groups(&self) -> Result<(), CardError> {
+        let sd_high_capacity = self.card_kind != CardKind::Mmc && self.ocr.high_capacity();
+        if sd_high_capacity || !self.csd.wp_group_enabled() {
+            return Err(CardError::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Check the capacity reported by the CSD without touching user data.
+    ///
+    /// The last block must read back and the block just past it must be
+    /// rejected with OUT_OF_RANGE; a card that returns data there silently
+    /// wraps around and overclaims its size. Returns the verified block count.
+    pub fn verify_capacity(&mut self) -> Result<u64, CardError> {
+        let blocks = self.block_count();
+        if blocks == 0 {
+            return Err(CardError::CardInitErr);
+        }
+        let _guard = LockGuard::new(self.lock);
+        let mut buf = [0u8; 512];
+        self.read_single(blocks as usize - 1, &mut buf)?;
+        self.mmc_opt.program_transfer(1, 512);
+        let past_end = self.card_address(blocks as usize);
+        match self.mmc_opt.send_cmd(read_single_block(past_end)) {
+            Ok(_) => {}
+            Err(CardError::StatusErr(StatusError::OutOfRange | StatusError::Address)) => {
+                self.mmc_opt.stop_transmission_ops()?;
+                return Ok(blocks);
+            }
+            Err(err) => {
+                self.mmc_opt.stop_transmission_ops()?;
+                return Err(err);
+            }
+        }
+        let ret = self.mmc_opt.read_data(&mut buf, 1, 512);
+        self.mmc_opt.stop_transmission_ops()?;
+        match ret {
+            Ok(_) => {
+                debug!("block {blocks} past the end is readable");
+                Err(CardError::CapacityMismatch)
+            }
+            Err(_) => Ok(blocks),
+        }
+    }
+
+    /// Make sure the card still works after a suspected glitch on the bus,
+    /// e.g. an ESD event, without it being removed.
+    ///
+    /// A card in transfer state is left alone. Otherwise the data path is
+    /// reset, a transfer or programming the card is still in is ended, and
+    /// the CID is read again at the stored RCA: the same card is selected
+    /// again, a different card or one that lost its address is identified
+    /// and configured from scratch. Not locked, like `init`.
+    pub fn revalidate(&mut self) -> Result<(), CardError> {
+        if !self.rca.is_valid() {
+            return Err(CardError::CardInitErr);
+        }
+        let state = self
+            .mmc_opt
+            .card_status(self.rca)
+            .map(|status| status.state());
+        match state {
+            Ok(CurrentState::Transfer) => return Ok(()),
+            Ok(state) => info!("revalidate: card in {state:?}"),
+            Err(err) => info!("revalidate: CMD13 failed: {err}"),
         }
+        self.mmc_opt
+            .reset_data_path(self.transfer_mode == TransferMode::Dma)?;
+        match state {
+            Ok(CurrentState::Sending | CurrentState::Receiving) => {
+                self.mmc_opt.stop_transmission_ops()?;
+            }
+            Ok(CurrentState::Programming) => {
+                if let Err(err) = self
+                    .mmc_opt
+                    .wait_programmed(self.rca, CardError::DataTransferTimeout)
+                {
+                    debug!("revalidate: {err}");
+                }
+            }
+            _ => {}
+        }
+        if self.same_card_present() {
+            if self.mmc_opt.card_status(self.rca)?.state() != CurrentState::Transfer {
+                self.mmc_opt.sel_card(self.rca)?;
+            }
+            return Ok(());
+        }
+        info!("revalidate: card changed or reset, identify again");
+        self.event(format_args!("card changed or reset"));
+        self.init_controller()?;
+        self.identify()?;
+        self.configure_for_io()
     }
-}
-impl Device for DwMmcHost {
-    fn init(&mut self) -> Result<(), DeviceError> {
-        info!("init sdio...");
+
+    /// Identify and configure the card just swapped into the slot, without
+    /// switching the slot power off as `close` followed by `init` would.
+    ///
+    /// The old card is deselected if it is still answering, the driver
+    /// forgets what it knew about it and the controller is reset with power
+    /// kept on, then the new card goes through identification and
+    /// [`Self::configure_for_io`]. The host signal level is set back to
+    /// 3.3V unless [`InitVoltage::V18`] is selected; the regulator from
+    /// [`Self::with_signal_voltage`] has no way back and is left alone.
+    pub fn replace_card(&mut self) -> Result<CardSummary, CardError> {
+        if self.rca.is_valid() {
+            if let Err(err) = self.mmc_opt.send_cmd(deselect_card()) {
+                debug!("replace card: old card did not deselect: {err}");
+            }
+        }
+        self.reset_to_idle()?;
+        if self.init_voltage != InitVoltage::V18 {
+            let uhs = read_reg::<u32>(self.sdio_base, REG_UHS);
+            write_reg::<u32>(self.sdio_base, REG_UHS, uhs & !UhsMask::volt_reg.bits());
+        }
+        self.init_controller()?;
+        self.identify()?;
+        self.configure_for_io()?;
+        info!("replacement card ready");
+        self.event(format_args!("replacement card ready"));
+        Ok(self.summary())
+    }
+
+    /// Send CMD0, reset the FIFO and DMA interface and forget everything
+    /// learned about the card, so that the next `init` starts from scratch,
+    /// e.g. before another card is inserted in the slot.
+    pub fn reset_to_idle(&mut self) -> Result<(), CardError> {
+        self.mmc_opt.send_cmd(idle())?;
+        self.mmc_opt.reset_data_path(true)?;
+        self.mmc_opt.set_ddr(false);
+        self.mmc_opt.set_write_factor(0);
+        self.mmc_opt.set_write_timeout_ms(None);
+        self.measured_write_us = None;
+        self.rca = Rca::new();
+        self.ocr = Ocr::new();
+        self.cic = Cic::new();
+        self.cid = Cid::new();
+        self.csd = Csd::new();
+        self.cid_suspect = false;
+        self.csd_suspect = false;
+        self.scr = None;
+        self.sd_status = None;
+        self.ext_csd = None;
+        self.speed_mode = SpeedMode::Default;
+        self.card_kind = CardKind::Sd;
+        self.current_limit = None;
+        Ok(())
+    }
+
+    /// Get the card ready to be pulled out: wait until it has finished
+    /// programming, deselect it with CMD7 and forget its RCA, so that the next
+    /// `init` identifies whatever card is in the slot by then. The driver
+    /// buffers no data, so there is nothing else to flush. The controller is
+    /// left powered, unlike `close`.
+    pub fn prepare_removal(&mut self) -> Result<(), CardError> {
+        if !self.rca.is_valid() {
+            return Ok(());
+        }
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt
+            .wait_programmed(self.rca, Timeout::WaitTranState.into())?;
+        self.mmc_opt.send_cmd(deselect_card())?;
+        self.rca = Rca::new();
+        Ok(())
+    }
+
+    /// Set the card clock to the fastest rate not above `target_hz` that the
+    /// divider can make from [`Self::with_source_clock_hz`], and return that
+    /// rate. Before a card has been identified the target is capped at
+    /// 400 kHz.
+    pub fn set_clock_hz(&mut self, target_hz: u32) -> Result<u32, CardError> {
+        let target = if self.rca.is_valid() {
+            target_hz
+        } else {
+            target_hz.min(IDENT_CLOCK_HZ)
+        };
+        self.apply_clock(target)?;
+        Ok(self.clock_hz)
+    }
+
+    /// Program the data block size in `BLKSIZ` and check that the controller
+    /// kept it. Every data command programs its own size, so this is for
+    /// checking which sizes a controller takes.
+    pub fn set_data_block_size(&mut self, bytes: u16) -> Result<(), CardError> {
+        self.mmc_opt.set_block_size(u32::from(bytes))
+    }
+
+    /// Give up on the data phase of a transfer after `ms`, in software and
+    /// in the hardware data timeout of `TMOUT`, which counts card clock
+    /// cycles and is reprogrammed on every clock change.
+    ///
+    /// 0 turns the software timeout off and leaves only the hardware one, at
+    /// its maximum. Without a call both run at their maximum.
+    pub fn set_data_timeout_ms(&mut self, ms: u64) {
+        self.data_timeout_ms = Some(ms);
+        self.mmc_opt.set_data_timeout_ms(ms);
+        self.program_data_timeout();
+    }
+
+    /// Write `TMOUT`: the data timeout in card clock cycles, saturating at
+    /// the 24-bit field, and the longest response timeout.
+    fn program_data_timeout(&self) {
+        let cycles = match self.data_timeout_ms {
+            Some(ms) if ms != 0 && self.clock_hz != 0 => {
+                (ms.saturating_mul(u64::from(self.clock_hz)) / 1000).min(0xFF_FFFF) as u32
+            }
+            _ => 0xFF_FFFF,
+        };
+        write_reg::<u32>(self.sdio_base, REG_TMOUT, cycles << 8 | 0xFF);
+    }
+
+    /// Override the `FIFOTH` watermarks and IDMAC burst size that `init`
+    /// derives from the FIFO depth: half the FIFO for both watermarks. The
+    /// burst must be one of 1, 4, 8, 16, 32, 64, 128 or 256 entries and at
+    /// most `rx_wmark + 1`. Applied at once after `init`, otherwise by it.
+    pub fn set_fifo_threshold(&mut self, threshold: FifoThreshold) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt.set_fifo_threshold(threshold)
+    }
+
+    /// Bytes moved so far by the data transfer in progress, or by the last
+    /// one, from the controller counters. A count that still grows between
+    /// two reads tells a slow transfer from a hung one; it drops back to 0
+    /// when the next data command starts.
+    pub fn bytes_transferred(&self) -> ByteCounts {
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt.bytes_transferred()
+    }
+
+    /// Card clock last programmed, 0 before `init`.
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Whether the card clock is enabled in `CLKENA`. Commands fail with
+    /// [`CardError::ClockNotRunning`] while it is not, rather than timing out.
+    pub fn clock_active(&self) -> bool {
+        self.mmc_opt.clock_active()
+    }
+
+    /// Whether `CDETECT` reports a card in the slot right now.
+    pub fn card_present(&self) -> bool {
+        read_reg::<u32>(self.sdio_base, REG_CDETECT) & 1 == 0
+    }
+
+    /// Whether the write protect switch of the slot is engaged, from
+    /// `WRTPRT`. Writes fail with [`CardError::WriteProtected`] while it is,
+    /// unless [`Self::with_write_protect_switch`] says the slot has none, and
+    /// while the CSD marks the card permanently or temporarily write
+    /// protected.
+    pub fn is_write_protected(&self) -> bool {
+        read_reg::<u32>(self.sdio_base, REG_WRTPRT) & 1 != 0
+    }
+
+    /// Refuse a write up front when the slot switch or the CSD protects the
+    /// card, rather than letting it fail mid-transfer. eMMC devices have no
+    /// switch, so only their CSD is checked.
+    fn check_writable(&self) -> Result<(), CardError> {
+        let switch = self.write_protect_switch && self.card_kind != CardKind::Mmc;
+        if switch && self.is_write_protected() {
+            debug!("write protect switch engaged");
+            return Err(CardError::WriteProtected);
+        }
+        if self.csd.perm_write_protect() || self.csd.tmp_write_protect() {
+            debug!("card write protected by its CSD");
+            return Err(CardError::WriteProtected);
+        }
+        Ok(())
+    }
+
+    /// Report a card insertion or removal since the previous call. The first
+    /// call reports a card already present as inserted.
+    ///
+    /// In [`CardDetectMode::Interrupt`] only a latched card detect interrupt
+    /// is looked at, and cleared; in polling mode `CDETECT` is read every
+    /// time. A bounce that ends in the previous state reports nothing.
+    pub fn poll_card_event(&self) -> Option<CardEvent> {
+        if self.card_detect_mode == CardDetectMode::Interrupt {
+            let cd = InterruptMask::cd.bits();
+            if read_reg::<u32>(self.sdio_base, REG_RINTSTS) & cd == 0 {
+                return None;
+            }
+            write_reg::<u32>(self.sdio_base, REG_RINTSTS, cd);
+        }
+        let present = self.card_present();
+        if self.card_present.replace(present) == present {
+            return None;
+        }
+        Some(if present {
+            CardEvent::Inserted
+        } else {
+            CardEvent::Removed
+        })
+    }
+
+    /// Whether the card detect interrupt has latched a change of `CDETECT`
+    /// since the previous call, cleared by the call. Cheaper than
+    /// [`Self::poll_card_event`] for polling loops, but without the
+    /// direction; both consume the same latch.
+    pub fn card_detect_changed(&self) -> bool {
+        let cd = InterruptMask::cd.bits();
+        if read_reg::<u32>(self.sdio_base, REG_RINTSTS) & cd == 0 {
+            return false;
+        }
+        write_reg::<u32>(self.sdio_base, REG_RINTSTS, cd);
+        true
+    }
+
+    /// Raw `RINTSTS`, for watching interrupt bits while bringing up a board.
+    /// The driver clears this register on every command, so read it from a
+    /// debugger hook or right after a failed call.
+    pub fn raw_interrupt_status(&self) -> u32 {
+        read_reg::<u32>(self.sdio_base, REG_RINTSTS)
+    }
+
+    /// Clear the `RINTSTS` bits set in `mask`, bypassing the driver. For
+    /// low-level debugging only: clearing a bit the driver is waiting on
+    /// makes the pending operation time out.
+    pub fn clear_interrupts(&mut self, mask: u32) {
+        write_reg::<u32>(self.sdio_base, REG_RINTSTS, mask);
+    }
+
+    /// Send CMD13 to the card for its status in `format`. The R1 card status
+    /// is the default; the task status is meant for command queueing.
+    pub fn send_status(&mut self, format: StatusFormat) -> Result<Status, CardError> {
+        self.mmc_opt.send_status(self.rca, format)
+    }
+
+    /// Whether the card holds DAT0 low, read from `STATUS.data_busy` without
+    /// sending a command. A card still programming a write reports busy;
+    /// use this for tight polling and CMD13 when the card state is needed.
+    pub fn data_line_busy(&self) -> bool {
+        read_reg::<u32>(self.sdio_base, REG_STATUS) & StatusMask::data_busy.bits() != 0
+    }
+
+    /// Work out the most likely reason a card does not work, for board
+    /// bring-up.
+    ///
+    /// Checks `CDETECT`, runs the whole init at 1-bit and classifies the
+    /// first failure, waits for DAT0 to leave busy, then reads block 0 a few
+    /// times at 1-bit and at the widest width card and controller allow.
+    /// Failures only at the wider bus point at DAT1 to DAT3 (DAT7 on eMMC),
+    /// failures that come and go at a flaky card.
+    ///
+    /// The card is left configured for whatever the last check needed; call
+    /// `init` again before using it.
+    pub fn diagnose(&mut self) -> Diagnosis {
+        if !self.card_present() {
+            return Diagnosis::NoCard;
+        }
+        let width = self.bus_width;
+        self.bus_width = BusWidth::One;
+        let ret = self
+            .init_controller()
+            .and_then(|_| self.identify())
+            .and_then(|_| self.configure_for_io());
+        self.bus_width = width;
+        if let Err(err) = ret {
+            debug!("diagnose: init failed: {err:?}");
+            return Diagnosis::from_error(err);
+        }
+        if self.data_line_busy() {
+            self.delay.spin_millis(500);
+            if self.data_line_busy() {
+                debug!("diagnose: DAT0 stuck busy");
+                return Diagnosis::DataLineFault;
+            }
+        }
+        let narrow = self.diagnose_reads();
+        if narrow == DIAGNOSE_READS {
+            return Diagnosis::DataLineFault;
+        }
+        let mut wide = 0;
+        let widest = [BusWidth::Eight, BusWidth::Four]
+            .into_iter()
+            .find(|&width| self.check_bus_width(width).is_ok());
+        if let Some(width) = widest {
+            if let Err(err) = self.switch_bus(width) {
+                debug!("diagnose: {width:?} switch failed: {err:?}");
+                return Diagnosis::from_error(err);
+            }
+            wide = self.diagnose_reads();
+        }
+        debug!("diagnose: read errors 1-bit {narrow}, {widest:?} {wide}");
+        match (narrow, wide) {
+            (0, 0) => Diagnosis::Healthy,
+            (0, _) => Diagnosis::DataLineFault,
+            _ => Diagnosis::FlakyCard,
+        }
+    }
+
+    /// Number of failed reads out of [`DIAGNOSE_READS`] reads of block 0.
+    fn diagnose_reads(&mut self) -> usize {
+        let mut buf = [0u8; 512];
+        (0..DIAGNOSE_READS)
+            .filter(|_| self.read_single(0, &mut buf).is_err())
+            .count()
+    }
+
+    /// Read the CID again with CMD10, addressed by the stored RCA, and
+    /// update the cached copy.
+    ///
+    /// CMD10 is only accepted in stand-by state, so a selected card is briefly
+    /// deselected and then selected again; its bus width and speed are kept.
+    pub fn refresh_cid_addressed(&mut self) -> Result<&Cid, CardError> {
+        self.mmc_opt.take_crc_suspect();
+        self.cid = self.mmc_opt.send_cid(self.rca)?;
+        self.cid_suspect = self.mmc_opt.take_crc_suspect();
+        Ok(&self.cid)
+    }
+
+    /// Whether the stored CID and CSD were read with a valid CRC. Only
+    /// `false` with [`Self::with_r2_crc_quirk`], after a register was decoded
+    /// from a response that failed the CRC check.
+    ///
+    /// The capacity and the block range checks come from the CSD and are not
+    /// to be relied on while it is suspect. The state is cleared when the
+    /// register is read again cleanly: by the next identification, or for
+    /// the CID by [`Self::refresh_cid_addressed`].
+    pub fn registers_trustworthy(&self) -> bool {
+        !self.cid_suspect && !self.csd_suspect
+    }
+
+    /// Read the SD Status with ACMD13 and keep it for
+    /// [`Self::registers`]. It carries the speed class, UHS speed grade and
+    /// allocation unit size. eMMC devices have none and fail with
+    /// [`CardError::Unsupported`].
+    pub fn sd_status(&mut self) -> Result<SdStatus, CardError> {
+        if self.card_kind == CardKind::Mmc {
+            return Err(CardError::Unsupported);
+        }
+        let _guard = LockGuard::new(self.lock);
+        let status = self.mmc_opt.read_sd_status(self.rca)?;
+        self.sd_status = Some(status);
+        Ok(status)
+    }
+
+    /// Read the SD Status with ACMD13 into `buf` as sent by the card, most
+    /// significant byte first, for fields [`SdStatus`] does not decode.
+    pub fn read_sd_status_raw(&mut self, buf: &mut [u8; 64]) -> Result<(), CardError> {
+        if self.card_kind == CardKind::Mmc {
+            return Err(CardError::Unsupported);
+        }
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt.read_sd_status_raw(self.rca, buf)
+    }
+
+    /// Read the SCR again with ACMD51 and update the stored copy, which
+    /// `init` fills in and the bus width and CMD23 choices rely on.
+    pub fn read_scr(&mut self) -> Result<Scr, CardError> {
+        if self.card_kind == CardKind::Mmc {
+            return Err(CardError::Unsupported);
+        }
+        let _guard = LockGuard::new(self.lock);
+        let scr = self.mmc_opt.read_scr(self.rca)?;
+        self.scr = Some(scr);
+        Ok(scr)
+    }
+
+    /// Read the SCR with ACMD51 into `buf` as sent by the card, most
+    /// significant byte first. The stored SCR is not updated.
+    pub fn read_scr_raw(&mut self, buf: &mut [u8; 8]) -> Result<(), CardError> {
+        if self.card_kind == CardKind::Mmc {
+            return Err(CardError::Unsupported);
+        }
+        let _guard = LockGuard::new(self.lock);
+        self.mmc_opt.read_scr_raw(self.rca, buf)
+    }
+
+    /// The CID read during identification.
+    pub fn cid_info(&self) -> &Cid {
+        &self.cid
+    }
+
+    /// The CSD read during identification.
+    pub fn csd_info(&self) -> &Csd {
+        &self.csd
+    }
+
+    /// Manufacturing date and, for eMMC, the wear estimate from the Ext CSD.
+    pub fn health(&self) -> CardHealth {
+        let remaining = |est: u8| match est {
+            1..=10 => Some(100 - est * 10),
+            11 => Some(0),
+            _ => None,
+        };
+        CardHealth {
+            manufacturing_date: self.cid.manufacturing_date(),
+            wear: self.ext_csd.map(|ext| WearEstimate {
+                life_remaining_a: remaining(ext.life_time_est_a()),
+                life_remaining_b: remaining(ext.life_time_est_b()),
+                pre_eol: ext.pre_eol_info(),
+            }),
+        }
+    }
+
+    /// Everything known about the card in one place, for diagnostics.
+    pub fn registers(&self) -> CardRegisters {
+        CardRegisters {
+            cid: self.cid,
+            csd: self.csd,
+            ocr: self.ocr,
+            rca: self.rca,
+            cic: self.cic,
+            scr: self.scr,
+            sd_status: self.sd_status,
+        }
+    }
+
+    /// Run `op` until it succeeds, fails with an error that is not
+    /// [`Transient`], or [`Self::with_transfer_retries`] attempts are used
+    /// up. The card is sent back to transfer state with CMD12 before each
+    /// retry.
+    fn retry_transient(
+        &self,
+        mut op: impl FnMut() -> Result<(), CardError>,
+    ) -> Result<(), CardError> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match op() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let Some(last) = Transient::classify(&err) else {
+                return Err(err);
+            };
+            if attempts >= self.transfer_retries {
+                if attempts == 1 {
+                    return Err(err);
+                }
+                warn!("{} after {} attempts", last, attempts);
+                return Err(CardError::RetriesExhausted { attempts, last });
+            }
+            debug!("retry transfer after {}", last);
+            self.event(format_args!("retry transfer after {last}"));
+            let _ = self.mmc_opt.stop_transmission_ops();
+            self.delay.spin_millis(TRANSFER_RETRY_DELAY_MS);
+        }
+    }
+
+    fn read_single(&self, lba: usize, buf: &mut [u8]) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.check_range(17, lba, 1)?;
+        trace!("read block, address: {},", lba);
+        let cmd = read_single_block(self.card_address(lba));
+        let blk_sz = self.block_size() as u32;
+        let ret = match self.dma_descs() {
+            Some(descs) => self.mmc_opt.read_data_dma(cmd, descs, buf, blk_sz),
+            None => {
+                let blk = buf.len() as u32 / blk_sz;
+                self.mmc_opt.program_transfer(blk, blk_sz);
+                self.mmc_opt.send_cmd(cmd).and_then(|resp| {
+                    let status = resp.card_status();
+                    debug!("{status:?}");
+                    self.mmc_opt.read_data(buf, blk, blk_sz)
+                })
+            }
+        };
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.mmc_opt
+                .recover_single_block(self.transfer_mode == TransferMode::Dma);
+        }
+        ret
+    }
+
+    /// Keep the error of a block transfer for [`Self::last_error`] and map it
+    /// for the `BlockDevice` interface.
+    fn record(&self, ret: Result<(), CardError>) -> Result<(), DeviceError> {
+        ret.map_err(|err| {
+            self.event(format_args!("transfer failed: {err}"));
+            self.last_error.set(Some(err));
+            DeviceError::from(err)
+        })
+    }
+
+    fn write_single(&self, lba: usize, data: &[u8]) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.check_range(24, lba, 1)?;
+        let cmd = write_single_block(self.card_address(lba));
+        let blk_sz = self.block_size() as u32;
+        let ret = match self.dma_descs() {
+            Some(descs) => self.mmc_opt.write_data_dma(cmd, descs, data, blk_sz),
+            None => {
+                let blk = data.len() as u32 / blk_sz;
+                self.mmc_opt.program_transfer(blk, blk_sz);
+                self.mmc_opt.send_cmd(cmd).and_then(|resp| {
+                    let status = resp.card_status();
+                    debug!("{status:?}");
+                    self.mmc_opt.write_data(data, blk, blk_sz)
+                })
+            }
+        };
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.mmc_opt
+                .recover_single_block(self.transfer_mode == TransferMode::Dma);
+            return ret;
+        }
+        self.mmc_opt
+            .wait_programmed(self.rca, CardError::DataTransferTimeout)
+    }
+
+    /// Reject `count` blocks from `lba` for command `cmd` unless they fit the
+    /// 32-bit block address and, once the card is identified, its capacity.
+    fn check_range(&self, cmd: u8, lba: usize, count: usize) -> Result<(), CardError> {
+        let blocks = self.block_count();
+        let end = lba as u64 + count as u64;
+        if lba > u32::MAX as usize || (blocks != 0 && end > blocks) {
+            debug!("CMD{cmd} blocks {lba}..{end} outside of card, {blocks} blocks");
+            return Err(CardError::InvalidArgument { cmd, arg: lba });
+        }
+        Ok(())
+    }
+
+    /// Command argument addressing block `lba` on this card, see
+    /// [`block_address`].
+    fn card_address(&self, lba: usize) -> u32 {
+        block_address(self.ocr.high_capacity(), lba)
+    }
+
+    /// Append one line to the event log when it is enabled.
+    fn event(&self, args: core::fmt::Arguments) {
+        if !self.event_log {
+            return;
+        }
+        if let Ok(mut log) = self.events.try_borrow_mut() {
+            let _ = writeln!(log, "{args}");
+        }
+    }
+
+    fn step<T, E: Into<CardError>>(
+        &mut self,
+        step: InitStep,
+        ret: Result<T, E>,
+    ) -> Result<T, CardError> {
+        if let Some(clock) = self.init_clock {
+            let now = clock();
+            let us = now.saturating_sub(self.init_mark);
+            let slot = &mut self.init_timings[step as usize];
+            *slot = slot.saturating_add(us.min(u32::MAX as u64) as u32);
+            self.init_mark = now;
+        }
+        ret.map_err(|err| {
+            let error = err.into();
+            debug!("init failed at {step:?}: {error}");
+            self.event(format_args!("init failed at {step:?}: {error}"));
+            self.init_error = Some(InitFailure {
+                step,
+                error,
+                regs: self.mmc_opt.snapshot(),
+            });
+            error
+        })
+    }
+
+    /// Time the programming of block `lba` rewritten with its own data and
+    /// size the write timeout from it.
+    fn calibrate_write(&mut self, lba: usize, clock: MicrosClock) -> Result<(), CardError> {
+        self.measured_write_us = None;
+        self.mmc_opt.set_write_timeout_ms(None);
+        let mut buf = [0u8; 512];
+        self.read_single(lba, &mut buf)?;
+        let start = clock();
+        self.write_single(lba, &buf)?;
+        let us = clock().saturating_sub(start);
+        debug!("block {lba} programmed in {us} us");
+        self.measured_write_us = Some(us.min(u32::MAX as u64) as u32);
+        let ms = (us * 10).div_ceil(1000).max(MIN_WRITE_TIMEOUT_MS);
+        self.mmc_opt.set_write_timeout_ms(Some(ms));
+        Ok(())
+    }
+
+    /// Run the card clock at the fastest rate not above `target_hz`.
+    fn apply_clock(&mut self, target_hz: u32) -> Result<(), CardError> {
+        let div = self.divider_for(target_hz);
+        self.mmc_opt.reset_clock(1, div)?;
+        self.clock_hz = clock_hz_for(self.source_clock_hz, div);
+        debug!("card clock {} Hz, divider {}", self.clock_hz, div);
+        self.program_data_timeout();
+        Ok(())
+    }
+
+    /// Divider for `target_hz`, or for the forced clock if one is set.
+    fn divider_for(&self, target_hz: u32) -> u32 {
+        let hz = self.force_clock_hz.unwrap_or(target_hz);
+        clock_divider_for(self.source_clock_hz, hz)
+    }
+
+    fn summary(&self) -> CardSummary {
+        CardSummary {
+            cid: self.cid,
+            csd: self.csd,
+            ocr: self.ocr,
+            rca: self.rca,
+            trustworthy: self.registers_trustworthy(),
+        }
+    }
+
+    fn init_controller(&mut self) -> Result<(), CardError> {
+        self.init_error = None;
+        self.init_timings = [0; InitStep::ALL.len()];
+        if let Some(clock) = self.init_clock {
+            self.init_mark = clock();
+        }
         let hconf = HardConfig::from_bits(read_reg::<u32>(self.sdio_base, REG_HCON)).unwrap();
         debug!("{hconf:?}");
         self.hard_config = HardConf::from(hconf.bits());
@@ -59,36 +2248,241 @@ impl Device for DwMmcHost {
             | ControlMask::fifo_reset.bits()
             | ControlMask::dma_reset.bits();
         write_reg::<u32>(self.sdio_base, REG_CTRL, reset_mask);
-        self.mmc_opt.wait_reset(reset_mask)?;
+        self.step(
+            InitStep::ControllerReset,
+            self.mmc_opt.wait_reset(reset_mask),
+        )?;
+        self.delay.spin_micros(self.post_reset_delay_us);
+        self.mmc_opt.probe_fifo_depth();
+        self.mmc_opt.program_fifo_threshold();
+        if let Some(hook) = self.board_init {
+            hook(self.sdio_base);
+        }
+        if self.init_voltage == InitVoltage::V18 {
+            if let Some(regulator) = self.signal_voltage {
+                if !regulator.set_1v8() {
+                    error!("signal regulator did not switch to 1.8V");
+                }
+            }
+            let uhs = read_reg::<u32>(self.sdio_base, REG_UHS);
+            write_reg::<u32>(self.sdio_base, REG_UHS, uhs | UhsMask::volt_reg.bits());
+        }
         // enable power
         write_reg::<u32>(self.sdio_base, REG_PWREN, 1);
-        self.mmc_opt.reset_clock(1, 62)?;
-        write_reg::<u32>(self.sdio_base, REG_TMOUT, 0xFFFFFFFF);
+        let ret = self.apply_clock(IDENT_CLOCK_HZ);
+        self.step(InitStep::SetClock, ret)?;
+        self.program_data_timeout();
         // setup interrupt mask
         write_reg::<u32>(self.sdio_base, REG_RINTSTS, InterruptMask::all().bits());
         write_reg::<u32>(self.sdio_base, REG_INTMASK, 0);
+        if self.card_detect_mode == CardDetectMode::Interrupt {
+            write_reg::<u32>(self.sdio_base, REG_INTMASK, InterruptMask::cd.bits());
+            let ctrl = read_reg::<u32>(self.sdio_base, REG_CTRL);
+            write_reg::<u32>(
+                self.sdio_base,
+                REG_CTRL,
+                ctrl | ControlMask::int_enable.bits(),
+            );
+        }
+        write_reg::<u32>(self.sdio_base, REG_CTYPE, 0x0001_0001);
+        let ctype = read_reg::<u32>(self.sdio_base, REG_CTYPE);
+        self.max_bus_width = if ctype & 0x0001_0000 != 0 {
+            BusWidth::Eight
+        } else if ctype & 1 != 0 {
+            BusWidth::Four
+        } else {
+            BusWidth::One
+        };
+        debug!("max bus width {:?}", self.max_bus_width);
         write_reg::<u32>(self.sdio_base, REG_CTYPE, 1);
         write_reg::<u32>(self.sdio_base, REG_IDINTEN, 0);
         write_reg::<u32>(self.sdio_base, REG_BMOD, 1);
+        self.select_transfer_mode();
+        self.mmc_opt.set_skip_cmd_settle(self.skip_cmd_settle);
+        Ok(())
+    }
+
+    /// Whether the card identified by a previous init is still in the slot:
+    /// CDETECT reports a card and CMD10 at the stored RCA returns the cached
+    /// CID. A removed, replaced or reset card fails CMD10 or compares unequal.
+    fn same_card_present(&self) -> bool {
+        if !self.rca.is_valid() || !self.card_present() {
+            return false;
+        }
+        match self.mmc_opt.send_cid(self.rca) {
+            Ok(cid) => cid == self.cid,
+            Err(err) => {
+                debug!("cached card gone: {err:?}");
+                false
+            }
+        }
+    }
+
+    /// Warm path of `init`: keep the card of a previous init when
+    /// [`Self::same_card_present`] finds it, and select it if it is not.
+    ///
+    /// Neither the controller nor the card is reset, so both keep the bus
+    /// width, speed mode, signal voltage and clock negotiated before, and
+    /// the cached registers stay valid. A controller that can no longer talk
+    /// to the card fails CMD10 and gets the cold path instead.
+    fn resume_known_card(&mut self) -> bool {
+        if !self.same_card_present() {
+            return false;
+        }
+        let ret = self
+            .mmc_opt
+            .card_status(self.rca)
+            .and_then(|status| match status.state() {
+                CurrentState::Transfer => Ok(()),
+                _ => self.mmc_opt.sel_card(self.rca),
+            });
+        match ret {
+            Ok(()) => {
+                self.init_error = None;
+                true
+            }
+            Err(err) => {
+                debug!("cached card not selectable: {err:?}");
+                false
+            }
+        }
+    }
 
+    fn select_transfer_mode(&mut self) {
+        self.transfer_mode = TransferMode::Pio;
+        self.pio_reason = None;
+        if !self.use_dma {
+            return;
+        }
+        if self.hard_config.0 & HardConfig::dma_interface.bits() == 0 {
+            self.transfer_mode = TransferMode::Dma;
+            return;
+        }
+        let reason = "controller uses an external DMA interface, no IDMAC";
+        info!("fall back to PIO: {reason}");
+        self.pio_reason = Some(reason);
+    }
+
+    fn identify(&mut self) -> Result<(), CardError> {
+        if let Some(pullup) = self.cmd_pullup {
+            pullup.open_drain();
+        }
         // // enumerate card stack
-        self.mmc_opt.send_cmd(idle())?;
+        for i in 0..self.idle_retries.max(1) {
+            if i != 0 {
+                self.delay.spin_millis(1);
+            }
+            self.step(InitStep::GoIdle, self.mmc_opt.send_cmd(idle()))?;
+        }
+        // A previous boot stage may have left a transfer running with the data
+        // line held busy. CMD12 with stop_abort_cmd frees the controller data
+        // path; the card itself is idle now and need not answer.
+        if let Err(err) = self.mmc_opt.stop_transmission_ops() {
+            debug!("stop after idle: {err:?}");
+        }
         self.delay.spin_millis(10);
-        self.cic = self.mmc_opt.check_version()?;
-        self.ocr = self.mmc_opt.check_v18_sdhc()?;
-        self.cid = self.mmc_opt.check_cid()?;
-        self.rca = self.mmc_opt.check_rca()?;
-        self.csd = self.mmc_opt.check_csd(self.rca)?;
-        self.mmc_opt.sel_card(self.rca)?;
-        self.mmc_opt.function_switch(16777201)?;
-        self.mmc_opt.set_bus(self.rca)?;
-        self.mmc_opt.reset_clock(1, 1)?;
-        write_reg::<u32>(
-            self.sdio_base,
-            REG_IDINTEN,
-            (DmaIntEn::ri | DmaIntEn::ti).bits(),
-        );
+        self.cic = match self.mmc_opt.check_version() {
+            Err(CardError::InterruptErr(Interrupt::ResponseTimeout)) if self.probe_mmc() => {
+                return self.identify_mmc();
+            }
+            ret => self.step(InitStep::SendIfCond, ret)?,
+        };
+        let io = self.step(InitStep::IoSendOpCond, self.mmc_opt.check_io())?;
+        self.card_kind = match io {
+            None => CardKind::Sd,
+            Some((_, true)) => CardKind::Combo,
+            Some((_, false)) => CardKind::Io,
+        };
+        if self.card_kind == CardKind::Io {
+            info!("I/O-only card, no memory to drive");
+            return self.step(InitStep::IoSendOpCond, Err(CardError::Unsupported));
+        }
+        let switch = self.init_voltage == InitVoltage::V33ThenV18;
+        let ret = self.mmc_opt.check_v18_sdhc(switch);
+        self.ocr = self.step(InitStep::SendOpCond, ret)?;
+        if switch && self.signal_voltage.is_some() && self.ocr.v18_allowed() {
+            let ret = self.switch_to_1v8();
+            self.step(InitStep::VoltageSwitch, ret)?;
+        }
+        self.mmc_opt.take_crc_suspect();
+        self.cid = self.step(InitStep::AllSendCid, self.mmc_opt.check_cid())?;
+        self.cid_suspect = self.mmc_opt.take_crc_suspect();
+        self.rca = self.step(InitStep::SendRelativeAddr, self.mmc_opt.check_rca())?;
+        if let Some(pullup) = self.cmd_pullup {
+            pullup.push_pull();
+        }
+        self.csd = self.step(InitStep::SendCsd, self.mmc_opt.check_csd(self.rca, false))?;
+        self.csd_suspect = self.mmc_opt.take_crc_suspect();
+        if self.csd_suspect {
+            warn!("CSD read with a CRC error, capacity may be wrong");
+        }
+        self.mmc_opt.set_write_factor(self.csd.r2w_factor());
+        Ok(())
+    }
+
+    /// After an unanswered CMD8, whether an eMMC device answers CMD1 from a
+    /// fresh CMD0.
+    fn probe_mmc(&mut self) -> bool {
+        debug!("no answer to CMD8, probe eMMC");
+        let ret = self
+            .mmc_opt
+            .send_cmd(idle())
+            .and_then(|_| self.mmc_opt.send_cmd(send_op_cond(0)));
+        match ret {
+            Ok(_) => true,
+            Err(err) => {
+                debug!("no eMMC: {err:?}");
+                false
+            }
+        }
+    }
+
+    /// eMMC identification: CMD1 for the OCR, CMD2, then CMD3 assigning the
+    /// RCA instead of asking the card for one.
+    fn identify_mmc(&mut self) -> Result<(), CardError> {
+        self.card_kind = CardKind::Mmc;
+        self.cic = Cic::new();
+        let host_ocr = match self.init_voltage {
+            InitVoltage::V18 => MMC_HOST_OCR_1V8,
+            _ => MMC_HOST_OCR,
+        };
+        let ret = self.mmc_opt.check_mmc_op_cond(host_ocr);
+        self.ocr = self.step(InitStep::SendOpCond, ret)?;
+        self.mmc_opt.take_crc_suspect();
+        self.cid = self.step(InitStep::AllSendCid, self.mmc_opt.check_cid())?;
+        self.cid_suspect = self.mmc_opt.take_crc_suspect();
+        let ret = self.mmc_opt.assign_rca(MMC_RCA);
+        self.rca = self.step(InitStep::SendRelativeAddr, ret)?;
+        if let Some(pullup) = self.cmd_pullup {
+            pullup.push_pull();
+        }
+        self.csd = self.step(InitStep::SendCsd, self.mmc_opt.check_csd(self.rca, true))?;
+        self.csd_suspect = self.mmc_opt.take_crc_suspect();
+        if self.csd_suspect {
+            warn!("CSD read with a CRC error, capacity may be wrong");
+        }
+        self.mmc_opt.set_write_factor(self.csd.r2w_factor());
+        Ok(())
+    }
+}
+impl Device for DwMmcHost {
+    fn init(&mut self) -> Result<(), DeviceError> {
+        info!("init sdio...");
+        if self.resume_known_card() {
+            info!("card unchanged, keep its configuration");
+            self.event(format_args!("init reused card, {:?}", self.card_kind));
+            return Ok(());
+        }
+        self.init_controller()?;
+        self.identify()?;
+        self.configure_for_io()?;
+        if let Some((lba, clock)) = self.write_calibration {
+            if let Err(err) = self.calibrate_write(lba, clock) {
+                info!("write calibration failed: {err}");
+            }
+        }
         info!("sdio init success!");
+        self.event(format_args!("init done, {:?}", self.card_kind));
         Ok(())
     }
 
@@ -119,54 +2513,21 @@ impl BlockDevice for DwMmcHost {
     }
 
     fn read_block(&mut self, lba: usize, buf: &mut [u8]) -> Result<(), DeviceError> {
-        trace!("read block, address: {},", lba);
-        let cmd = read_single_block(lba as u32);
-        match self.mmc_opt.send_cmd(cmd) {
-            Ok(resp) => {
-                let status = resp.card_status();
-                debug!("{status:?}");
-                let blk_sz = self.block_size() as u32;
-                let blk = buf.len() as u32 / blk_sz;
-                match self.mmc_opt.read_data(buf, blk, blk_sz) {
-                    Ok(_) => Ok(()),
-                    Err(err) => {
-                        debug!("{err:?}");
-                        self.mmc_opt.stop_transmission_ops()?;
-                        Err(DeviceError::IoError)
-                    }
-                }
-            }
-            Err(err) => {
-                debug!("{err:?}");
-                self.mmc_opt.stop_transmission_ops()?;
-                Err(DeviceError::IoError)
-            }
-        }
+        let _guard = LockGuard::new(self.lock);
+        let ret = self.retry_transient(|| self.read_single(lba, buf));
+        self.record(ret)
     }
 
+    // `&self` is fixed by the `tom_device` trait, which this crate does not
+    // own. Code holding the host mutably should call `write_blocks`, which
+    // takes `&mut self` like `read_block`; shared callers are serialized by
+    // the lock from `with_lock`.
     fn write_block(&self, lba: usize, data: &[u8]) -> Result<(), DeviceError> {
-        let cmd = write_single_block(lba as u32);
-        match self.mmc_opt.send_cmd(cmd) {
-            Ok(resp) => {
-                let status = resp.card_status();
-                debug!("{status:?}");
-                let blk_sz = self.block_size() as u32;
-                let blk = data.len() as u32 / blk_sz;
-                match self.mmc_opt.write_data(data,blk,blk_sz) {
-                    Ok(_) => Ok(()),
-                    Err(err) => {
-                        debug!("{err:?}");
-                        self.mmc_opt.stop_transmission_ops()?;
-                        Err(DeviceError::IoError)
-                    }
-                }
-            }
-            Err(err) => {
-                debug!("{err:?}");
-                self.mmc_opt.stop_transmission_ops()?;
-                Err(DeviceError::IoError)
-            }
-        }
+        let _guard = LockGuard::new(self.lock);
+        let ret = self
+            .check_writable()
+            .and_then(|_| self.retry_transient(|| self.write_single(lba, data)));
+        self.record(ret)
     }
 
     fn information(&self) -> &dyn BlkDevInfo {
@@ -189,3 +2550,62 @@ impl DwMMC {
 }
 
 impl BlkDevInfo for DwMMC {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdsc_capacity_probe_uses_byte_addresses() {
+        // CSD 1.0 of a 2 GB SDSC card, 1024-byte READ_BL_LEN
+        let csd = Csd::from((0x1280_40ED, 0xB6DB_FF80, 0x5F5A_83C5, 0x0026_0032));
+        let blocks = (csd.card_size() / 512) as usize;
+        assert_eq!(blocks, 3_955_712);
+        assert_eq!(block_address(false, blocks - 1), 2_025_324_032);
+        assert_eq!(block_address(false, blocks), 2_025_324_544);
+        assert_eq!(block_address(true, blocks), 3_955_712);
+    }
+
+    #[test]
+    fn divider_at_or_above_base_is_bypass() {
+        assert_eq!(clock_divider_for(50_000_000, 50_000_000), 0);
+        assert_eq!(clock_divider_for(50_000_000, 200_000_000), 0);
+    }
+
+    #[test]
+    fn divider_rounds_up_to_stay_below_target() {
+        assert_eq!(clock_divider_for(50_000_000, 25_000_000), 1);
+        assert_eq!(clock_divider_for(50_000_000, 30_000_000), 1);
+        assert_eq!(clock_divider_for(50_000_000, 24_000_000), 2);
+        assert_eq!(clock_divider_for(50_000_000, 400_000), 63);
+    }
+
+    #[test]
+    fn divider_saturates_for_low_targets() {
+        assert_eq!(clock_divider_for(50_000_000, 0), 0xFF);
+        assert_eq!(clock_divider_for(50_000_000, 1_000), 0xFF);
+        assert_eq!(clock_divider_for(u32::MAX, 1), 0xFF);
+    }
+
+    #[test]
+    fn clock_for_divider() {
+        assert_eq!(clock_hz_for(50_000_000, 0), 50_000_000);
+        assert_eq!(clock_hz_for(50_000_000, 1), 25_000_000);
+        assert_eq!(clock_hz_for(50_000_000, 63), 396_825);
+        assert_eq!(clock_hz_for(100_000_000, 0xFF), 196_078);
+    }
+
+    #[test]
+    fn achieved_clock_never_exceeds_target() {
+        for base in [24_000_000, 50_000_000, 100_000_000, 200_000_000] {
+            for target in [IDENT_CLOCK_HZ, DEFAULT_CLOCK_HZ, HIGH_SPEED_CLOCK_HZ] {
+                let div = clock_divider_for(base, target);
+                let hz = clock_hz_for(base, div);
+                assert!(hz <= target, "{base} Hz / {div} gives {hz} Hz");
+                if div > 0 {
+                    assert!(clock_hz_for(base, div - 1) > target);
+                }
+            }
+        }
+    }
+}
@@ -1,32 +1,319 @@
+use crate::bus::{Mmio, RegisterBus};
+use crate::clock::{Clock, CountDown, TickerClock};
 use crate::cmd::*;
+use crate::hooks::{CmdLock, DmaCoherency, LockGuard};
 use crate::reg::*;
 use crate::sd_reg::*;
-use log::{debug, error};
+use core::cell::Cell;
+use core::sync::atomic::{fence, Ordering};
+use log::{debug, error, info, warn};
 
-use tom_device::{read_reg, write_reg};
-use tom_timer::Delay;
-use tom_timer::MillisCountDown;
 use tom_timer::Ticker;
 
 use super::err::*;
 
-pub(super) struct MmcOperate {
-    sdio_base: usize,
-    ticker: &'static dyn Ticker,
-    delay: Delay,
+/// When `send_cmd` waits for the FIFO reset to complete after a data command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoResetPolicy {
+    /// After every data command
+    Always,
+    /// Only when the transfer direction differs from the previous data
+    /// command, i.e. not between back-to-back reads or back-to-back writes
+    OnDirectionChange,
 }
 
-impl MmcOperate {
+/// Byte counters of the current or last data transfer. The controller
+/// clears both when a data command starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// `TCBCNT`: bytes moved between the card and the controller
+    pub card: u32,
+    /// `TBBCNT`: bytes moved between the host and the FIFO
+    pub host: u32,
+}
+
+/// FIFO watermarks and IDMAC burst size, in FIFO entries, as programmed in
+/// `FIFOTH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FifoThreshold {
+    /// RXDR is raised, and the IDMAC reads, above this many entries
+    pub rx_wmark: u16,
+    /// TXDR is raised, and the IDMAC writes, at or below this many entries
+    pub tx_wmark: u16,
+    /// Entries moved by one IDMAC burst: 1, 4, 8, 16, 32, 64, 128 or 256
+    pub burst: u16,
+}
+
+pub(super) struct MmcOperate<B: RegisterBus = Mmio, C: Clock = TickerClock> {
+    bus: B,
+    clock: C,
+    write_factor: u8,
+    fifo_depth: u32,
+    fifo_count_mask: u32,
+    cmd_gap_us: u64,
+    fifo_reset_policy: FifoResetPolicy,
+    last_write: Cell<Option<bool>>,
+    fifo_batch: u32,
+    fifo_threshold: Option<FifoThreshold>,
+    coherency: Option<&'static dyn DmaCoherency>,
+    hle_retries: u8,
+    supply_mv: Option<u16>,
+    write_timeout_ms: Option<u64>,
+    data_timeout_ms: u64,
+    skip_cmd_settle: bool,
+    r2_crc_quirk: bool,
+    crc_suspect: Cell<bool>,
+    strict: bool,
+    auto_stop: bool,
+    lock: Option<&'static dyn CmdLock>,
+}
+
+impl MmcOperate<Mmio> {
     pub const fn new(sdio_base: usize, ticker: &'static dyn Ticker) -> Self {
+        Self::with_bus(Mmio::new(sdio_base), TickerClock::new(ticker))
+    }
+}
+
+impl<B: RegisterBus, C: Clock> MmcOperate<B, C> {
+    /// Driver logic on the registers behind `bus`, timed by `clock`.
+    pub const fn with_bus(bus: B, clock: C) -> Self {
         Self {
-            sdio_base,
-            ticker,
-            delay: Delay::new(ticker),
+            bus,
+            clock,
+            write_factor: 0,
+            fifo_depth: 0,
+            fifo_count_mask: StatusMask::fifo_count.bits() >> FIFO_COUNT_SHIFT,
+            cmd_gap_us: 0,
+            fifo_reset_policy: FifoResetPolicy::Always,
+            last_write: Cell::new(None),
+            fifo_batch: 0,
+            fifo_threshold: None,
+            coherency: None,
+            hle_retries: 8,
+            supply_mv: None,
+            write_timeout_ms: None,
+            data_timeout_ms: DATA_TMOUT_DEFUALT as u64,
+            skip_cmd_settle: false,
+            r2_crc_quirk: false,
+            crc_suspect: Cell::new(false),
+            strict: false,
+            auto_stop: false,
+            lock: None,
+        }
+    }
+
+    pub const fn with_fifo_reset_policy(mut self, policy: FifoResetPolicy) -> Self {
+        self.fifo_reset_policy = policy;
+        self
+    }
+
+    pub const fn with_command_gap_us(mut self, us: u64) -> Self {
+        self.cmd_gap_us = us;
+        self
+    }
+
+    pub const fn with_supply_mv(mut self, mv: u16) -> Self {
+        self.supply_mv = Some(mv);
+        self
+    }
+
+    pub const fn with_hle_retries(mut self, retries: u8) -> Self {
+        self.hle_retries = retries;
+        self
+    }
+
+    pub const fn with_r2_crc_quirk(mut self, enable: bool) -> Self {
+        self.r2_crc_quirk = enable;
+        self
+    }
+
+    /// Check R2 responses against their CRC7 in software and fail on
+    /// COM_CRC_ERROR in R1 and R6 responses.
+    pub const fn with_strict(mut self, enable: bool) -> Self {
+        self.strict = enable;
+        self
+    }
+
+    /// End CMD18 and CMD25 transfers with the controller's auto-stop instead
+    /// of a CMD12 sent by software.
+    pub const fn with_auto_stop(mut self, enable: bool) -> Self {
+        self.auto_stop = enable;
+        self
+    }
+
+    /// Whether an R2 response with a CRC error was decoded since the last
+    /// call, under the R2 CRC quirk.
+    pub fn take_crc_suspect(&self) -> bool {
+        self.crc_suspect.replace(false)
+    }
+
+    /// Hold `lock` over every command, and over the whole command sequence
+    /// and data phase of the operations made of several. `acquire` is called
+    /// again by a task already holding the lock, which must allow it.
+    pub const fn with_lock(mut self, lock: &'static dyn CmdLock) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    pub const fn with_dma_coherency(mut self, coherency: &'static dyn DmaCoherency) -> Self {
+        self.coherency = Some(coherency);
+        self
+    }
+
+    /// Entries moved out of the FIFO each time RXDR is serviced, 0 for the
+    /// watermark.
+    pub const fn with_fifo_batch(mut self, entries: u32) -> Self {
+        self.fifo_batch = entries;
+        self
+    }
+
+    /// Record the FIFO depth, taken from the reset value of `FIFOTH.RX_WMARK`,
+    /// and size the `STATUS.fifo_count` mask to it. An unset batch size
+    /// defaults to that watermark.
+    pub fn probe_fifo_depth(&mut self) {
+        if self.fifo_depth != 0 {
+            return;
+        }
+        let fifoth = self.bus.read32(REG_FIFOTH);
+        self.fifo_depth = ((fifoth & FifoThMask::rx_wmark.bits()) >> RX_WMARK_SHIFT) + 1;
+        if self.fifo_batch == 0 {
+            self.fifo_batch = self.fifo_depth;
+        }
+        let count_max = StatusMask::fifo_count.bits() >> FIFO_COUNT_SHIFT;
+        self.fifo_count_mask = ((self.fifo_depth + 1).next_power_of_two() - 1).min(count_max);
+        debug!(
+            "fifo depth {}, count mask {:#x}",
+            self.fifo_depth, self.fifo_count_mask
+        );
+    }
+
+    /// FIFO entries in a 512 byte block, the entry width being the host data
+    /// width from `HCON.H_DATA_WIDTH`.
+    fn fifo_entries_per_block(&self) -> u16 {
+        let hcon = self.bus.read32(REG_HCON);
+        match (hcon & HardConfig::h_data_width.bits()) >> 7 {
+            0 => 256,
+            2 => 64,
+            _ => 128,
+        }
+    }
+
+    /// Watermarks at half the FIFO and the largest burst that evenly splits
+    /// both halves and a 512 byte block of FIFO entries.
+    fn default_fifo_threshold(&self) -> FifoThreshold {
+        let half = (self.fifo_depth / 2).max(1) as u16;
+        let entries = self.fifo_entries_per_block();
+        let burst = DMA_BURSTS
+            .into_iter()
+            .rev()
+            .find(|burst| half.is_multiple_of(*burst) && entries.is_multiple_of(*burst))
+            .unwrap_or(1);
+        FifoThreshold {
+            rx_wmark: half - 1,
+            tx_wmark: half,
+            burst,
+        }
+    }
+
+    /// `FIFOTH` value for `threshold`, `None` if it does not fit the FIFO.
+    fn fifoth_bits(&self, threshold: FifoThreshold) -> Option<u32> {
+        let mts = DMA_BURSTS
+            .iter()
+            .position(|burst| *burst == threshold.burst)? as u32;
+        let (rx, tx) = (u32::from(threshold.rx_wmark), u32::from(threshold.tx_wmark));
+        if rx >= self.fifo_depth || tx >= self.fifo_depth || rx + 1 < u32::from(threshold.burst) {
+            return None;
+        }
+        Some(mts << DMA_MTS_SHIFT | rx << RX_WMARK_SHIFT | tx)
+    }
+
+    /// Use `threshold` instead of the default from the next
+    /// [`Self::program_fifo_threshold`] on, and right away once the FIFO
+    /// depth is known. Fails with [`CardError::Unsupported`] for a burst not
+    /// in [`DMA_BURSTS`], or watermarks the FIFO cannot hold.
+    pub fn set_fifo_threshold(&mut self, threshold: FifoThreshold) -> Result<(), CardError> {
+        if !DMA_BURSTS.contains(&threshold.burst) {
+            return Err(CardError::Unsupported);
+        }
+        if self.fifo_depth != 0 {
+            let bits = self.fifoth_bits(threshold).ok_or(CardError::Unsupported)?;
+            self.bus.write32(REG_FIFOTH, bits);
+        }
+        self.fifo_threshold = Some(threshold);
+        Ok(())
+    }
+
+    /// Program `FIFOTH` from the threshold set with
+    /// [`Self::set_fifo_threshold`], or the default for the FIFO depth,
+    /// which must have been probed.
+    pub fn program_fifo_threshold(&self) {
+        let default = self.default_fifo_threshold();
+        let threshold = self.fifo_threshold.unwrap_or(default);
+        let bits = match self.fifoth_bits(threshold) {
+            Some(bits) => bits,
+            None => {
+                warn!(
+                    "{threshold:?} does not fit a {} entry fifo",
+                    self.fifo_depth
+                );
+                self.fifoth_bits(default).unwrap_or(0)
+            }
+        };
+        debug!("fifoth {bits:#x}");
+        self.bus.write32(REG_FIFOTH, bits);
+    }
+
+    pub fn bytes_transferred(&self) -> ByteCounts {
+        ByteCounts {
+            card: self.bus.read32(REG_TCBCNT),
+            host: self.bus.read32(REG_TBBCNT),
         }
     }
+
+    fn fifo_count(&self) -> u32 {
+        (self.bus.read32(REG_STATUS) >> FIFO_COUNT_SHIFT) & self.fifo_count_mask
+    }
+
+    /// Scale the write timeout by `1 << factor`, from the CSD R2W_FACTOR.
+    pub fn set_write_factor(&mut self, factor: u8) {
+        self.write_factor = factor.min(7);
+    }
+
+    /// Skip the settle time after each command, so that the next one is
+    /// loaded as soon as the CIU has taken the previous one.
+    pub fn set_skip_cmd_settle(&mut self, skip: bool) {
+        self.skip_cmd_settle = skip;
+    }
+
+    /// Bound the wait for write programming to `ms`, or go back to the
+    /// timeout derived from R2W_FACTOR with `None`.
+    pub fn set_write_timeout_ms(&mut self, ms: Option<u64>) {
+        self.write_timeout_ms = ms;
+    }
+
+    /// Software timeout of the data phase of a transfer, 0 for none, which
+    /// leaves it to the hardware data timeout.
+    pub fn set_data_timeout_ms(&mut self, ms: u64) {
+        self.data_timeout_ms = ms;
+    }
+
+    fn write_timeout(&self) -> u64 {
+        let base = match self.data_timeout_ms {
+            0 => DATA_TMOUT_DEFUALT as u64,
+            ms => ms,
+        };
+        let generic = base << self.write_factor;
+        self.write_timeout_ms.map_or(generic, |ms| ms.min(generic))
+    }
+
+    /// Count down `ms` for a data phase, unless the software data timeout
+    /// is turned off.
+    fn data_countdown(&self, ms: u64) -> Option<C::CountDown> {
+        (self.data_timeout_ms != 0).then(|| self.clock.count_down(ms))
+    }
     fn wait_for_cmd_line(&self) -> Result<(), Timeout> {
         if !self.wait_for(0xFF, || {
-            read_reg::<u32>(self.sdio_base, REG_CMD) & CmdMask::start_cmd.bits() == 0
+            self.bus.read32(REG_CMD) & CmdMask::start_cmd.bits() == 0
         }) {
             Err(Timeout::WaitCmdLine)
         } else {
@@ -35,8 +322,13 @@ impl MmcOperate {
     }
 
     fn wait_for_data_line(&self) -> Result<(), Timeout> {
-        if self.wait_for(DATA_TMOUT_DEFUALT as u64, || {
-            read_reg::<u32>(self.sdio_base, REG_STATUS) & StatusMask::data_busy.bits() == 0
+        self.wait_data_idle(DATA_TMOUT_DEFUALT as u64)
+    }
+
+    /// Wait up to `millis` for the card to release DAT0.
+    fn wait_data_idle(&self, millis: u64) -> Result<(), Timeout> {
+        if self.wait_for(millis, || {
+            self.bus.read32(REG_STATUS) & StatusMask::data_busy.bits() == 0
         }) {
             Ok(())
         } else {
@@ -46,7 +338,7 @@ impl MmcOperate {
 
     fn wait_for_cmd_done(&self) -> Result<(), Timeout> {
         if self.wait_for(0xFF, || {
-            read_reg::<u32>(self.sdio_base, REG_RINTSTS) & InterruptMask::cmd.bits() != 0
+            self.bus.read32(REG_RINTSTS) & InterruptMask::cmd.bits() != 0
         }) {
             Ok(())
         } else {
@@ -55,165 +347,840 @@ impl MmcOperate {
     }
 
     pub fn wait_reset(&self, mask: u32) -> Result<(), Timeout> {
-        if self.wait_for(10, || read_reg::<u32>(self.sdio_base, REG_CTRL) & mask == 0) {
+        if self.wait_for(10, || self.bus.read32(REG_CTRL) & mask == 0) {
             Ok(())
         } else {
             Err(Timeout::WaitReset)
         }
     }
 
+    /// Clear the `RINTSTS` bits in `mask` except card detect, which stays
+    /// latched until the card event is polled.
+    pub fn clear_status(&self, mask: u32) {
+        self.bus
+            .write32(REG_RINTSTS, mask & !InterruptMask::cd.bits());
+    }
+
+    /// Clear what a finished data phase left in RINTSTS, except ACD, which
+    /// [`Self::wait_auto_stop`] still has to see.
+    fn clear_data_status(&self) {
+        self.clear_status(self.bus.read32(REG_RINTSTS) & !InterruptMask::acd.bits());
+    }
+
+    /// Wait for the CMD12 the controller sends at the end of an auto-stop
+    /// transfer and for the card to leave busy, then clear ACD.
+    fn wait_auto_stop(&self) -> Result<(), CardError> {
+        let acd = InterruptMask::acd.bits();
+        let done = self.wait_for(DATA_TMOUT_DEFUALT as u64, || {
+            self.bus.read32(REG_RINTSTS) & acd != 0
+        });
+        self.clear_status(acd);
+        if self.bus.read32(REG_RINTSTS) & acd != 0 {
+            warn!("ACD still set after clearing it");
+        }
+        if !done {
+            error!("no auto-stop done interrupt");
+            return Err(Timeout::WaitCmdDone.into());
+        }
+        // the response to the auto-stop is kept in RESP1
+        let status = Response::R48(self.bus.read32(REG_RESP1)).card_status();
+        debug!("auto-stop {status:?}");
+        self.wait_for_data_line()?;
+        Ok(())
+    }
+
+    pub fn clock_active(&self) -> bool {
+        self.bus.read32(REG_CLKENA) & ClkEnaMask::cclk_enable.bits() != 0
+    }
+
+    /// Read and discard the words `STATUS.fifo_count` reports, so that no
+    /// stale word survives the FIFO reset that follows. Returns the number
+    /// of words dropped.
+    fn drain_fifo(&self) -> u32 {
+        let words = self.fifo_count();
+        for _ in 0..words {
+            self.bus.read32(REG_DATA);
+        }
+        if words != 0 {
+            debug!("drained {words} stale fifo words");
+        }
+        words
+    }
+
+    /// Drain and reset the FIFO, and reset the DMA interface too if `dma`.
+    pub fn reset_data_path(&self, dma: bool) -> Result<(), Timeout> {
+        self.drain_fifo();
+        let mut mask = ControlMask::fifo_reset.bits();
+        if dma {
+            mask |= ControlMask::dma_reset.bits();
+        }
+        let ctrl = self.bus.read32(REG_CTRL);
+        self.bus.write32(REG_CTRL, ctrl | mask);
+        self.wait_reset(mask)
+    }
+
+    /// Clean up after a failed transfer, once the card has ended it: by
+    /// itself for a single block, after CMD12 for multiple blocks. Only the
+    /// FIFO is reset, so that words left from the aborted block do not end
+    /// up in the next transfer; a failing reset is logged, leaving the
+    /// transfer error to be reported.
+    pub fn recover_single_block(&self, dma: bool) {
+        if let Err(err) = self.reset_data_path(dma) {
+            error!("fifo reset after failed transfer: {err}");
+        }
+    }
+
     pub fn send_cmd(&self, cmd: Command) -> Result<Response, CardError> {
+        self.send_cmd_busy(cmd, DATA_TMOUT_DEFUALT as u64)
+    }
+
+    /// [`Self::send_cmd`], waiting up to `busy_ms` for the busy signal of an
+    /// R1b response instead of the default.
+    fn send_cmd_busy(&self, cmd: Command, busy_ms: u64) -> Result<Response, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        if !self.clock_active() {
+            error!("card clock disabled, {:?}", cmd);
+            return Err(CardError::ClockNotRunning);
+        }
+        if self.cmd_gap_us != 0 {
+            self.clock.spin_micros(self.cmd_gap_us);
+        }
         self.wait_for_cmd_line()?;
-        write_reg(self.sdio_base, REG_RINTSTS, InterruptMask::all().bits());
+        self.clear_status(InterruptMask::all().bits());
 
         if cmd.data_exp() {
             self.wait_for_data_line()?;
         }
-        write_reg(self.sdio_base, REG_CMDARG, cmd.arg());
-        write_reg(self.sdio_base, REG_CMD, cmd.to_cmd());
+        self.bus.write32(REG_CMDARG, cmd.arg());
+        self.bus.write32(REG_CMD, cmd.to_cmd());
         self.wait_for_cmd_done()?;
         let resp = if cmd.resp_exp() {
-            let mask: u32 = read_reg(self.sdio_base, REG_RINTSTS);
+            let mask: u32 = self.bus.read32(REG_RINTSTS);
             if mask & InterruptMask::rto.bits() != 0 {
-                write_reg(self.sdio_base, REG_RINTSTS, mask);
+                self.clear_status(mask);
                 error!(
                     "Response Timeout, mask: {:?}",
                     InterruptMask::from_bits(mask).unwrap()
                 );
                 return Err(Interrupt::ResponseTimeout.into());
             } else if mask & InterruptMask::re.bits() != 0 {
-                write_reg(self.sdio_base, REG_RINTSTS, mask);
+                self.clear_status(mask);
                 error!(
                     "Response Error, mask : {:?}",
                     InterruptMask::from_bits(mask).unwrap()
                 );
                 return Err(Interrupt::ResponseErr.into());
             } else if mask & InterruptMask::rcrc.bits() != 0 {
-                error!(
-                    "Response CRC Error, mask: {:?}",
-                    InterruptMask::from_bits(mask).unwrap()
-                );
-                return Err(Interrupt::ResponseCrc.into());
+                if self.r2_crc_quirk && cmd.resp_lang() {
+                    warn!("R2 response CRC error ignored, {:?}", cmd);
+                    self.crc_suspect.set(true);
+                } else {
+                    error!(
+                        "Response CRC Error, mask: {:?}",
+                        InterruptMask::from_bits(mask).unwrap()
+                    );
+                    return Err(Interrupt::ResponseCrc.into());
+                }
             }
             if cmd.resp_lang() {
-                let resp0 = read_reg(self.sdio_base, REG_RESP0);
-                let resp1 = read_reg(self.sdio_base, REG_RESP1);
-                let resp2 = read_reg(self.sdio_base, REG_RESP2);
-                let resp3 = read_reg(self.sdio_base, REG_RESP3);
+                let resp0 = self.bus.read32(REG_RESP0);
+                let resp1 = self.bus.read32(REG_RESP1);
+                let resp2 = self.bus.read32(REG_RESP2);
+                let resp3 = self.bus.read32(REG_RESP3);
                 Response::R136((resp0, resp1, resp2, resp3))
             } else {
-                Response::R48(read_reg::<u32>(self.sdio_base, REG_RESP0))
+                Response::R48(self.bus.read32(REG_RESP0))
             }
         } else {
             Response::Rz
         };
+        if self.strict {
+            self.check_strict(cmd, resp)?;
+        }
+        if cmd.resp_r1() {
+            let status = resp.card_status();
+            if status.wp_violation() {
+                error!("write protect violation, {:?}", cmd);
+                return Err(CardError::WriteProtected);
+            }
+            if let Err(err) = StatusError::check(&status) {
+                error!("{err}: {:?}", status);
+                return Err(err.into());
+            }
+        }
+        if cmd.busy_exp() {
+            self.wait_data_idle(busy_ms)?;
+        }
         if cmd.data_exp() {
-            self.wait_reset(ControlMask::fifo_reset.bits())?;
+            let write = cmd.write_mode();
+            let same = self.last_write.replace(Some(write)) == Some(write);
+            if !(same && self.fifo_reset_policy == FifoResetPolicy::OnDirectionChange) {
+                self.wait_reset(ControlMask::fifo_reset.bits())?;
+            }
+        }
+        if !self.skip_cmd_settle {
+            self.clock.spin_micros(100);
+        }
+        Ok(resp)
+    }
+
+    /// Checks of [`Self::with_strict`] on top of the controller's. For short
+    /// responses `RESP0` only holds bits 39:8, the card status or register,
+    /// so the command index and CRC7 are left to the controller, which
+    /// always checks them: it raises RE on an index mismatch and RCRC on a
+    /// bad CRC. Only R2 keeps a CRC7 software can check, that of the CID or
+    /// CSD it carries.
+    fn check_strict(&self, cmd: Command, resp: Response) -> Result<(), CardError> {
+        if !resp.crc_matches(cmd) {
+            error!("R2 response CRC7 mismatch, {:?}", cmd);
+            return Err(CardError::ResponseCrcMismatch);
+        }
+        let com_crc = match resp {
+            Response::R48(_) if cmd.resp_r1() => resp.card_status().com_crc_error(),
+            // R6 carries COM_CRC_ERROR in bit 15
+            Response::R48(r) if cmd.resp_r6() => r & 1 << 15 != 0,
+            _ => false,
+        };
+        if com_crc {
+            error!("card reported COM_CRC_ERROR, {:?}", cmd);
+            return Err(CardError::CommandCrc);
+        }
+        Ok(())
+    }
+
+    /// Send a command that the card is allowed not to implement.
+    ///
+    /// ILLEGAL_COMMAND in the R1 status then means the feature is absent and
+    /// becomes [`CardError::Unsupported`] instead of a failure. Commands sent
+    /// this way: CMD6 (not on SD 1.0 cards) and CMD28/CMD29/CMD30 (no group
+    /// write protection on SDHC/SDXC). Everything else is mandatory and goes
+    /// through [`Self::send_cmd`].
+    pub fn send_optional_cmd(&self, cmd: Command) -> Result<Response, CardError> {
+        let resp = self.send_cmd(cmd)?;
+        if resp.card_status().illegal_command() {
+            debug!("{:?} not supported by card", cmd);
+            return Err(CardError::Unsupported);
         }
-        self.delay.spin_micros(100);
         Ok(resp)
     }
 
+    /// Write `BLKSIZ` and read it back. A controller that clamps or rounds
+    /// the size fails with [`CardError::Unsupported`]. Data commands program
+    /// their size with [`Self::program_transfer`] instead.
+    pub fn set_block_size(&self, bytes: u32) -> Result<(), CardError> {
+        self.bus.write32(REG_BLKSIZ, bytes);
+        let actual = self.bus.read32(REG_BLKSIZ) & 0xFFFF;
+        if actual != bytes {
+            error!("BLKSIZ {bytes} reads back as {actual}");
+            return Err(CardError::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Program `BLKSIZ` and `BYTCNT` for `blk` blocks of `blk_sz` bytes. The
+    /// controller takes both when the data command is issued, so this goes
+    /// before every [`Self::send_cmd`] with a data phase.
+    pub fn program_transfer(&self, blk: u32, blk_sz: u32) {
+        self.bus.write32(REG_BLKSIZ, blk_sz);
+        self.bus.write32(REG_BYTCNT, blk_sz * blk);
+    }
+
+    /// Read the `blk` blocks of `blk_sz` bytes of a transfer programmed with
+    /// [`Self::program_transfer`] before its command.
     pub fn read_data(&self, buf: &mut [u8], blk: u32, blk_sz: u32) -> Result<(), CardError> {
-        write_reg::<u32>(self.sdio_base, REG_BLKSIZ, blk_sz);
-        write_reg::<u32>(self.sdio_base, REG_BYTCNT, blk_sz * blk);
-        let size = (blk * blk_sz) as usize;
+        self.read_data_with(buf, blk, blk_sz, None)
+    }
+
+    /// [`Self::read_data`], calling `progress` with the bytes read so far
+    /// after each FIFO burst.
+    fn read_data_with(
+        &self,
+        buf: &mut [u8],
+        blk: u32,
+        blk_sz: u32,
+        progress: Option<&mut (dyn FnMut(usize) + '_)>,
+    ) -> Result<(), CardError> {
+        let len = (blk * blk_sz) as usize;
+        if buf.len() < len {
+            return Err(CardError::BufferLength);
+        }
+        self.read_fifo_with(&mut buf[..len], true, progress)
+    }
+
+    /// Fill `buf` from the receive FIFO of a transfer already programmed in
+    /// BLKSIZ and BYTCNT. With `last` the transfer must end with `buf`, and
+    /// data transfer over is awaited and cleared; otherwise it returns once
+    /// `buf` is full, leaving the transfer running.
+    ///
+    /// Never writes past `buf`: data still in the FIFO once the transfer is
+    /// over fails with [`CardError::DataOverrun`], and is left for the
+    /// caller's data path reset to drain.
+    pub fn read_fifo(&self, buf: &mut [u8], last: bool) -> Result<(), CardError> {
+        self.read_fifo_with(buf, last, None)
+    }
+
+    fn read_fifo_with(
+        &self,
+        buf: &mut [u8],
+        last: bool,
+        mut progress: Option<&mut (dyn FnMut(usize) + '_)>,
+    ) -> Result<(), CardError> {
+        let size = buf.len();
         let mut offset = 0;
-        let timer = MillisCountDown::new(DATA_TMOUT_DEFUALT as u64, self.ticker);
+        let timer = self.data_countdown(self.data_timeout_ms);
         loop {
-            let mask = read_reg::<u32>(self.sdio_base, REG_RINTSTS);
-            if offset == size && mask & InterruptMask::dto.bits() != 0 {
+            let mask = self.bus.read32(REG_RINTSTS);
+            if offset == size && (!last || mask & InterruptMask::dto.bits() != 0) {
                 break;
             }
+            // Only an error on reads: on writes the same bit is BCI, busy
+            // clear, on newer controllers.
+            if mask & InterruptMask::sbe.bits() != 0 {
+                return Err(CardError::StartBitError);
+            }
+            check_data_timeout(mask)?;
             Interrupt::check(mask)?;
-            self.delay.spin_micros(10);
-            if timer.timeout() {
+            self.clock.spin_micros(10);
+            if timer.as_ref().is_some_and(CountDown::timeout) {
                 return Err(CardError::DataTransferTimeout);
             }
             if mask & (InterruptMask::rxdr | InterruptMask::dto).bits() != 0 {
-                while (read_reg::<u32>(self.sdio_base, REG_STATUS) >> 17) & 0x1FFF != 0 {
-                    buf[offset] = read_reg::<u8>(self.sdio_base, REG_DATA + offset);
-                    offset += 1;
+                let mut moved = 0;
+                while moved < self.fifo_batch && offset < size && self.fifo_count() != 0 {
+                    offset += unpack_fifo_word(&mut buf[offset..], self.bus.read32(REG_DATA));
+                    moved += 1;
+                }
+                self.bus.write32(REG_RINTSTS, InterruptMask::rxdr.bits());
+                if let (Some(report), true) = (progress.as_mut(), moved != 0) {
+                    report(offset);
                 }
-                write_reg::<u32>(self.sdio_base, REG_RINTSTS, InterruptMask::rxdr.bits());
             }
         }
-        write_reg::<u32>(
-            self.sdio_base,
-            REG_RINTSTS,
-            read_reg::<u32>(self.sdio_base, REG_RINTSTS),
-        );
+        if last {
+            self.clear_data_status();
+            let left = self.fifo_count();
+            if left != 0 {
+                error!("{left} fifo entries left after {size} bytes");
+                return Err(CardError::DataOverrun);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send CMD18 for `blk` blocks from `addr` and program the transfer,
+    /// leaving the data to be collected with [`Self::read_fifo`].
+    pub fn start_read_stream(&self, addr: u32, blk: u32, blk_sz: u32) -> Result<(), CardError> {
+        self.program_transfer(blk, blk_sz);
+        let status = self.send_cmd(read_multiple_block(addr))?.card_status();
+        debug!("{status:?}");
+        Ok(())
+    }
+
+    /// CMD18 with the data phase through the FIFO, or the IDMAC when `descs`
+    /// is given, then CMD12, from the controller with [`Self::with_auto_stop`].
+    /// With `predefined` the block count is announced with CMD23 first and
+    /// CMD12 is only sent if the transfer fails. `progress` gets the bytes
+    /// moved so far after each FIFO burst, or once at the end with the
+    /// IDMAC.
+    pub fn read_multiple_block(
+        &self,
+        addr: u32,
+        buf: &mut [u8],
+        blk_sz: u32,
+        descs: Option<&[Cell<IdmacDesc>]>,
+        predefined: bool,
+        mut progress: Option<&mut (dyn FnMut(usize) + '_)>,
+    ) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        if predefined {
+            self.send_cmd(set_block_count(buf.len() as u32 / blk_sz))?;
+        }
+        let auto_stop = self.auto_stop && !predefined;
+        let mut cmd = read_multiple_block(addr);
+        if auto_stop {
+            cmd = cmd.with_auto_stop();
+        }
+        let ret = match descs {
+            Some(descs) => self.read_data_dma(cmd, descs, buf, blk_sz).inspect(|_| {
+                if let Some(report) = progress.as_mut() {
+                    report(buf.len());
+                }
+            }),
+            None => {
+                let blk = buf.len() as u32 / blk_sz;
+                self.program_transfer(blk, blk_sz);
+                self.send_cmd(cmd).and_then(|resp| {
+                    let status = resp.card_status();
+                    debug!("{status:?}");
+                    self.read_data_with(buf, blk, blk_sz, progress)
+                })
+            }
+        };
+        if let Err(err) = ret {
+            debug!("{err:?}");
+        }
+        let stop = if ret.is_err() || !(predefined || auto_stop) {
+            self.stop_transmission_ops()
+        } else if auto_stop {
+            self.wait_auto_stop()
+        } else {
+            Ok(())
+        };
+        self.finish_multiple_block(ret, stop, descs.is_some())
+    }
+
+    /// Run the read command `cmd` with the IDMAC filling `buf`.
+    pub fn read_data_dma(
+        &self,
+        cmd: Command,
+        descs: &[Cell<IdmacDesc>],
+        buf: &mut [u8],
+        blk_sz: u32,
+    ) -> Result<(), CardError> {
+        let (addr, len) = (buf.as_mut_ptr() as usize, buf.len());
+        let phys = dma_address(addr)?;
+        if let Some(coherency) = self.coherency {
+            coherency.invalidate(addr, len);
+        }
+        let ret = self.transfer_dma(cmd, descs, phys, len as u32, blk_sz, false);
+        if let Some(coherency) = self.coherency {
+            coherency.invalidate(addr, len);
+        }
+        ret
+    }
+
+    /// Run the write command `cmd` with the IDMAC sending `buf`.
+    pub fn write_data_dma(
+        &self,
+        cmd: Command,
+        descs: &[Cell<IdmacDesc>],
+        buf: &[u8],
+        blk_sz: u32,
+    ) -> Result<(), CardError> {
+        let (addr, len) = (buf.as_ptr() as usize, buf.len());
+        let phys = dma_address(addr)?;
+        if let Some(coherency) = self.coherency {
+            coherency.flush(addr, len);
+        }
+        self.transfer_dma(cmd, descs, phys, len as u32, blk_sz, true)
+    }
+
+    /// Run the data command `cmd` with the IDMAC moving `len` bytes at
+    /// `phys`, described by a chain built in `descs`.
+    ///
+    /// The descriptors are handed to the controller by their addresses, so
+    /// they must be identity mapped.
+    pub fn transfer_dma(
+        &self,
+        cmd: Command,
+        descs: &[Cell<IdmacDesc>],
+        phys: u32,
+        len: u32,
+        blk_sz: u32,
+        write: bool,
+    ) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let count = len.div_ceil(IDMAC_DESC_BUF_LEN) as usize;
+        if count == 0 || count > descs.len() {
+            return Err(CardError::BufferLength);
+        }
+        let first = dma_address(descs.as_ptr() as usize)?;
+        for (i, desc) in descs[..count].iter().enumerate() {
+            let offset = i as u32 * IDMAC_DESC_BUF_LEN;
+            let mut flags = (DescFlags::own | DescFlags::ch | DescFlags::dic).bits();
+            if i == 0 {
+                flags |= DescFlags::fs.bits();
+            }
+            let next = if i + 1 == count {
+                flags = (flags & !DescFlags::dic.bits()) | DescFlags::ld.bits();
+                0
+            } else {
+                first + (i as u32 + 1) * core::mem::size_of::<IdmacDesc>() as u32
+            };
+            desc.set(IdmacDesc {
+                des0: flags,
+                des1: (len - offset).min(IDMAC_DESC_BUF_LEN),
+                des2: phys + offset,
+                des3: next,
+            });
+        }
+        if let Some(coherency) = self.coherency {
+            coherency.flush(first as usize, core::mem::size_of_val(&descs[..count]));
+        }
+        fence(Ordering::SeqCst);
+        self.bus.write32(REG_IDSTS, DmaStatus::all().bits());
+        self.bus.write32(REG_DBADDR, first);
+        self.program_transfer(len / blk_sz, blk_sz);
+        self.set_idmac(true);
+        self.bus.write32(REG_PLDMND, 1);
+        let ret = self.send_cmd(cmd).and_then(|resp| {
+            let status = resp.card_status();
+            debug!("{status:?}");
+            self.wait_dma_done(write)
+        });
+        self.set_idmac(false);
+        ret
+    }
+
+    fn set_idmac(&self, enable: bool) {
+        let ctrl_mask = (ControlMask::use_internal_dmac | ControlMask::dma_enable).bits();
+        let bmod_mask = (BusModeMask::de | BusModeMask::fb).bits();
+        let ctrl = self.bus.read32(REG_CTRL);
+        let bmod = self.bus.read32(REG_BMOD);
+        if enable {
+            self.bus.write32(REG_CTRL, ctrl | ctrl_mask);
+            self.bus.write32(REG_BMOD, bmod | bmod_mask);
+        } else {
+            self.bus.write32(REG_BMOD, bmod & !bmod_mask);
+            self.bus.write32(REG_CTRL, ctrl & !ctrl_mask);
+        }
+    }
+
+    /// Stop the IDMAC after a fatal bus error: software reset of the DMA
+    /// engine, then the FIFO and DMA interface.
+    fn abort_dma(&self) {
+        let bmod = self.bus.read32(REG_BMOD);
+        self.bus.write32(REG_BMOD, bmod | BusModeMask::swr.bits());
+        if let Err(err) = self.reset_data_path(true) {
+            error!("dma reset after bus error: {err}");
+        }
+        self.bus.write32(REG_IDSTS, DmaStatus::all().bits());
+    }
+
+    fn wait_dma_done(&self, write: bool) -> Result<(), CardError> {
+        let done = if write { DmaStatus::ti } else { DmaStatus::ri };
+        let fault = (DmaStatus::du | DmaStatus::ces).bits();
+        let timer = self.data_countdown(self.data_timeout_ms);
+        loop {
+            let idsts = self.bus.read32(REG_IDSTS);
+            if idsts & DmaStatus::fbe.bits() != 0 {
+                let desc = self.bus.read32(REG_DSCADDR);
+                let buf = self.bus.read32(REG_BUFADDR);
+                error!(
+                    "IDMAC bus error, descriptor {desc:#x}, buffer {buf:#x}: {:?}",
+                    DmaStatus::from_bits_truncate(idsts)
+                );
+                self.abort_dma();
+                return Err(CardError::DmaBusError { desc });
+            }
+            if idsts & fault != 0 {
+                error!("IDMAC fault: {:?}", DmaStatus::from_bits_truncate(idsts));
+                return Err(CardError::DmaFault);
+            }
+            let mask = self.bus.read32(REG_RINTSTS);
+            if mask & InterruptMask::dto.bits() != 0 && idsts & done.bits() != 0 {
+                break;
+            }
+            if !write && mask & InterruptMask::sbe.bits() != 0 {
+                return Err(CardError::StartBitError);
+            }
+            check_data_timeout(mask)?;
+            Interrupt::check(mask)?;
+            if timer.as_ref().is_some_and(CountDown::timeout) {
+                return Err(CardError::DataTransferTimeout);
+            }
+        }
+        self.bus.write32(REG_IDSTS, DmaStatus::all().bits());
+        self.clear_data_status();
         Ok(())
     }
 
     pub fn write_data(&self, buf: &[u8], blk: u32, blk_sz: u32) -> Result<(), CardError> {
-        write_reg::<u32>(self.sdio_base, REG_BLKSIZ, blk_sz);
-        write_reg::<u32>(self.sdio_base, REG_BYTCNT, blk_sz * blk);
-        let timer = MillisCountDown::new(DATA_TMOUT_DEFUALT as u64, self.ticker);
+        self.write_data_with(buf, blk, blk_sz, None)
+    }
+
+    /// [`Self::write_data`], calling `progress` with the bytes written so
+    /// far after each FIFO burst.
+    fn write_data_with(
+        &self,
+        buf: &[u8],
+        blk: u32,
+        blk_sz: u32,
+        mut progress: Option<&mut (dyn FnMut(usize) + '_)>,
+    ) -> Result<(), CardError> {
+        let size = (blk * blk_sz) as usize;
+        let mut offset = 0;
+        let timer = self.data_countdown(self.write_timeout());
         loop {
-            let mask = read_reg::<u32>(self.sdio_base, REG_RINTSTS);
+            let mask = self.bus.read32(REG_RINTSTS);
             if InterruptMask::dto.bits() & mask != 0 {
                 break;
             }
+            check_data_timeout(mask)?;
             Interrupt::check(mask)?;
-            self.delay.spin_micros(10);
-            if timer.timeout() {
+            self.clock.spin_micros(10);
+            if timer.as_ref().is_some_and(CountDown::timeout) {
                 return Err(CardError::DataTransferTimeout);
             }
             if mask & InterruptMask::txdr.bits() != 0 {
-                for offset in 0..((blk * blk_sz) as usize) {
-                    write_reg::<u8>(self.sdio_base, REG_DATA + offset, buf[offset]);
+                let start = offset;
+                while offset < size
+                    && self.bus.read32(REG_STATUS) & StatusMask::fifo_full.bits() == 0
+                {
+                    let (word, len) = pack_fifo_word(&buf[offset..]);
+                    self.bus.write32(REG_DATA, word);
+                    offset += len;
+                }
+                self.bus.write32(REG_RINTSTS, InterruptMask::txdr.bits());
+                if let (Some(report), true) = (progress.as_mut(), offset != start) {
+                    report(offset);
                 }
-                write_reg::<u32>(self.sdio_base, REG_RINTSTS, InterruptMask::txdr.bits());
             }
         }
-        write_reg::<u32>(
-            self.sdio_base,
-            REG_RINTSTS,
-            read_reg::<u32>(self.sdio_base, REG_RINTSTS),
-        );
+        self.clear_data_status();
+        Ok(())
+    }
+
+    /// CMD25 with the data phase through the FIFO, or the IDMAC when `descs`
+    /// is given, then CMD12, from the controller with [`Self::with_auto_stop`].
+    /// With `predefined` the block count is announced with CMD23 first and
+    /// CMD12 is only sent if the transfer fails. `progress` gets the bytes
+    /// moved so far after each FIFO burst, or once at the end with the
+    /// IDMAC.
+    pub fn write_multiple_block(
+        &self,
+        addr: u32,
+        buf: &[u8],
+        blk_sz: u32,
+        descs: Option<&[Cell<IdmacDesc>]>,
+        predefined: bool,
+        mut progress: Option<&mut (dyn FnMut(usize) + '_)>,
+    ) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        if predefined {
+            self.send_cmd(set_block_count(buf.len() as u32 / blk_sz))?;
+        }
+        let auto_stop = self.auto_stop && !predefined;
+        let mut cmd = write_multiple_block(addr);
+        if auto_stop {
+            cmd = cmd.with_auto_stop();
+        }
+        let ret = match descs {
+            Some(descs) => self.write_data_dma(cmd, descs, buf, blk_sz).inspect(|_| {
+                if let Some(report) = progress.as_mut() {
+                    report(buf.len());
+                }
+            }),
+            None => {
+                let blk = buf.len() as u32 / blk_sz;
+                self.program_transfer(blk, blk_sz);
+                self.send_cmd(cmd).and_then(|resp| {
+                    let status = resp.card_status();
+                    debug!("{status:?}");
+                    self.write_data_with(buf, blk, blk_sz, progress)
+                })
+            }
+        };
+        if let Err(err) = ret {
+            debug!("{err:?}");
+        }
+        let stop = if ret.is_err() || !(predefined || auto_stop) {
+            self.stop_transmission_ops()
+        } else if auto_stop {
+            self.wait_auto_stop()
+        } else {
+            // no R1b of CMD12 to wait for, the card signals busy on DAT0
+            self.wait_for_data_line().map_err(CardError::from)
+        };
+        self.finish_multiple_block(ret, stop, descs.is_some())
+    }
+
+    /// Result of a multiple-block transfer from its data phase `ret` and
+    /// the `stop` that ended it. A failed data phase is recovered whether
+    /// or not the stop went through, and its error is the one returned.
+    fn finish_multiple_block(
+        &self,
+        ret: Result<(), CardError>,
+        stop: Result<(), CardError>,
+        dma: bool,
+    ) -> Result<(), CardError> {
+        if let Err(err) = ret {
+            if let Err(stop_err) = stop {
+                error!("stop after failed transfer: {stop_err}");
+            }
+            self.recover_single_block(dma);
+            return Err(err);
+        }
+        stop
+    }
+
+    /// ACMD23 ahead of a CMD25 of `count` blocks, so that the card can erase
+    /// them before the data arrives.
+    pub fn set_wr_blk_erase_count(&self, rca: Rca, count: u32) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.send_cmd(app_cmd(rca.address()))?;
+        let status = self.send_cmd(set_wr_blk_erase_count(count))?.card_status();
+        debug!("{status:?}");
         Ok(())
     }
 
+    /// Poll CMD13 until the card has finished programming and is back in
+    /// transfer state, ready for data. Gives up with `timeout` after the
+    /// write timeout, for a card stuck in programming state.
+    pub fn wait_programmed(&self, rca: Rca, timeout: CardError) -> Result<(), CardError> {
+        let mut state = None;
+        if self.wait_for(self.write_timeout(), || {
+            self.card_status(rca).is_ok_and(|status| {
+                state = Some(status.state());
+                status.state() == CurrentState::Transfer && status.ready_for_data()
+            })
+        }) {
+            Ok(())
+        } else {
+            warn!("card still programming, state {state:?}");
+            Err(timeout)
+        }
+    }
+
     pub fn reset_clock(&self, ena: u32, div: u32) -> Result<(), Timeout> {
         self.wait_for_cmd_line()?;
-        write_reg::<u32>(self.sdio_base, REG_CLKENA, 0);
-        write_reg::<u32>(self.sdio_base, REG_CLKDIV, div);
+        self.bus.write32(REG_CLKENA, 0);
+        self.bus.write32(REG_CLKDIV, div);
         let cmd = up_clk();
-        write_reg::<u32>(self.sdio_base, REG_CMDARG, cmd.arg());
-        write_reg::<u32>(self.sdio_base, REG_CMD, cmd.to_cmd());
+        self.bus.write32(REG_CMDARG, cmd.arg());
+        self.bus.write32(REG_CMD, cmd.to_cmd());
         if ena == 0 {
             return Ok(());
         }
         self.wait_for_cmd_line()?;
-        write_reg::<u32>(self.sdio_base, REG_CMD, cmd.to_cmd());
+        self.bus.write32(REG_CMD, cmd.to_cmd());
         self.wait_for_cmd_line()?;
-        write_reg::<u32>(self.sdio_base, REG_CLKENA, ena);
-        write_reg::<u32>(self.sdio_base, REG_CMDARG, 0);
-        write_reg::<u32>(self.sdio_base, REG_CMD, cmd.to_cmd());
+        self.bus.write32(REG_CLKENA, ena);
+        self.bus.write32(REG_CMDARG, 0);
+        self.bus.write32(REG_CMD, cmd.to_cmd());
         debug!("reset clock");
         Ok(())
     }
 
+    /// Run the CMD11 signal voltage switch, calling `set_1v8` to switch the
+    /// board supply while the card clock is stopped.
+    ///
+    /// The card answers CMD11 and holds CMD and DAT[3:0] low; the clock is
+    /// stopped, the controller moved to 1.8 V, and the clock restarted after
+    /// 5 ms. The card then releases the lines within 1 ms.
+    pub fn switch_voltage(&self, set_1v8: impl FnOnce() -> bool) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let status = self.send_cmd(voltage_switch())?.card_status();
+        debug!("{status:?}");
+        let busy = || self.bus.read32(REG_STATUS) & StatusMask::data_busy.bits() != 0;
+        if !self.wait_for(1, busy) {
+            error!("card did not drive DAT lines low after CMD11");
+            return Err(CardError::VoltageSwitch);
+        }
+        self.update_clock_for_switch(0)?;
+        if !set_1v8() {
+            error!("signal regulator did not switch to 1.8V");
+            return Err(CardError::VoltageSwitch);
+        }
+        let uhs = self.bus.read32(REG_UHS);
+        self.bus.write32(REG_UHS, uhs | UhsMask::volt_reg.bits());
+        self.clock.spin_millis(5);
+        self.update_clock_for_switch(ClkEnaMask::cclk_enable.bits())?;
+        if !self.wait_for(1, || !busy()) {
+            error!("card did not release DAT lines at 1.8V");
+            return Err(CardError::VoltageSwitch);
+        }
+        self.clear_status((InterruptMask::hto | InterruptMask::cmd).bits());
+        Ok(())
+    }
+
+    /// Write `CLKENA` with a clock update command flagged as part of the
+    /// voltage switch, which the controller requires during CMD11.
+    fn update_clock_for_switch(&self, ena: u32) -> Result<(), Timeout> {
+        self.wait_for_cmd_line()?;
+        self.bus.write32(REG_CLKENA, ena);
+        let cmd = up_clk();
+        self.bus.write32(REG_CMDARG, 0);
+        self.bus
+            .write32(REG_CMD, cmd.to_cmd() | CmdMask::volt_switch.bits());
+        self.wait_for_cmd_line()
+    }
+
+    pub fn set_ddr(&self, enable: bool) {
+        let uhs = self.bus.read32(REG_UHS);
+        let uhs = if enable {
+            uhs | UhsMask::ddr_reg.bits()
+        } else {
+            uhs & !UhsMask::ddr_reg.bits()
+        };
+        self.bus.write32(REG_UHS, uhs);
+    }
+
+    pub fn set_sample_phase(&self, phase: u8) {
+        let mask = UhsExtMask::clk_smpl_phase_ctrl.bits();
+        let ext = self.bus.read32(REG_UHS_REG_EXT) & !mask;
+        self.bus
+            .write32(REG_UHS_REG_EXT, ext | (u32::from(phase) << 16) & mask);
+    }
+
+    pub fn sample_phase(&self) -> u8 {
+        let ext = self.bus.read32(REG_UHS_REG_EXT);
+        ((ext & UhsExtMask::clk_smpl_phase_ctrl.bits()) >> 16) as u8
+    }
+
+    /// Read the CMD19 tuning block and compare it with the 4-bit pattern.
+    pub fn tuning_block_matches(&self) -> Result<bool, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let mut buf = [0u8; 64];
+        self.program_transfer(1, 64);
+        let ret = self.send_cmd(send_tuning_block()).and_then(|resp| {
+            let status = resp.card_status();
+            debug!("{status:?}");
+            self.read_data(&mut buf, 1, 64)
+        });
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.recover_single_block(false);
+            return Err(err);
+        }
+        Ok(buf == TUNING_BLOCK_4BIT)
+    }
+
     pub fn check_version(&self) -> Result<Cic, CardError> {
         let cmd = send_if_cond(1, 0xAA);
-        let cic = self.send_cmd(cmd)?.cic();
+        let cic = self
+            .send_cmd(cmd)?
+            .cic()
+            .ok_or(CardError::InvalidResponse)?;
         if cic.voltage_accepted() == 1 && cic.pattern() == 0xAA {
             debug!("sd vision 2.0");
-            self.delay.spin_millis(10);
+            self.clock.spin_millis(10);
             Ok(cic)
         } else {
             Err(CardError::VoltagePattern)
         }
     }
 
-    pub fn check_v18_sdhc(&self) -> Result<Ocr, CardError> {
+    /// Probe for SDIO functions with CMD5. Memory-only cards do not answer;
+    /// otherwise returns the number of I/O functions and whether memory is
+    /// present too.
+    pub fn check_io(&self) -> Result<Option<(u8, bool)>, CardError> {
+        debug!("probe sdio functions");
+        match self.send_cmd(io_send_op_cond(0)) {
+            Ok(resp) => {
+                let r4 = resp.io_ocr().ok_or(CardError::InvalidResponse)?;
+                let functions = ((r4 >> 28) & 0x7) as u8;
+                let memory = r4 & (1 << 27) != 0;
+                debug!("sdio functions: {functions}, memory present: {memory}");
+                Ok(Some((functions, memory)).filter(|(functions, _)| *functions != 0))
+            }
+            Err(CardError::InterruptErr(Interrupt::ResponseTimeout)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// ACMD41 until the card leaves busy, asking for 1.8 V signalling with
+    /// S18R when `s18r`.
+    pub fn check_v18_sdhc(&self, s18r: bool) -> Result<Ocr, CardError> {
         let ocr = loop {
             let cmd = app_cmd(0);
             let status = self.send_cmd(cmd)?.card_status();
             debug!("{status:?}");
-            let cmd = sd_send_op_cond(true, true);
-            let ocr = self.send_cmd(cmd)?.ocr();
+            let cmd = sd_send_op_cond(true, s18r);
+            let ocr = self
+                .send_cmd(cmd)?
+                .ocr()
+                .ok_or(CardError::InvalidResponse)?;
             if !ocr.is_busy() {
                 if ocr.high_capacity() {
                     debug!("card is high capacity!");
@@ -221,82 +1188,411 @@ impl MmcOperate {
                 if ocr.v18_allowed() {
                     debug!("card can switch to 1.8 voltage!");
                 }
+                self.check_supply(ocr);
                 break ocr;
             }
-            self.delay.spin_millis(10);
+            self.clock.spin_millis(10);
         };
-        self.delay.spin_millis(10);
+        self.clock.spin_millis(10);
         Ok(ocr)
     }
 
+    /// Warn when the board supply lies outside the voltage window the card
+    /// rates itself for in the OCR. Such cards often work at room temperature
+    /// and fail at the extremes.
+    fn check_supply(&self, ocr: Ocr) {
+        let Some(mv) = self.supply_mv else {
+            return;
+        };
+        match ocr.voltage_window_mv() {
+            Some((min, max)) if mv < min || mv > max => {
+                warn!("supply {mv} mV outside card window {min}..{max} mV");
+            }
+            Some(_) => {}
+            None => warn!("card reports no voltage window"),
+        }
+    }
+
+    /// CMD1 with the host OCR `host_ocr` until the eMMC device leaves busy.
+    /// Gives up after about a second.
+    pub fn check_mmc_op_cond(&self, host_ocr: u32) -> Result<Ocr, CardError> {
+        let cmd = send_op_cond(host_ocr);
+        for _ in 0..100 {
+            let ocr = self
+                .send_cmd(cmd)?
+                .ocr()
+                .ok_or(CardError::InvalidResponse)?;
+            if !ocr.is_busy() {
+                debug!("{:?}", ocr);
+                return Ok(ocr);
+            }
+            self.clock.spin_millis(10);
+        }
+        Err(CardError::CardInitErr)
+    }
+
+    /// Give the eMMC device the relative address `rca` with CMD3.
+    pub fn assign_rca(&self, rca: u16) -> Result<Rca, CardError> {
+        let status = self.send_cmd(set_relative_address(rca))?.card_status();
+        debug!("{:?}", status);
+        self.clock.spin_millis(10);
+        Ok(Rca::from(u32::from(rca) << 16))
+    }
+
+    /// Read the eMMC Ext CSD with CMD8.
+    pub fn read_ext_csd(&self) -> Result<ExtCsd, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let mut buf = [0u8; 512];
+        self.program_transfer(1, 512);
+        let ret = self.send_cmd(send_ext_csd()).and_then(|resp| {
+            let status = resp.card_status();
+            debug!("{status:?}");
+            self.read_data(&mut buf, 1, 512)
+        });
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.recover_single_block(false);
+            return Err(err);
+        }
+        let ext = ExtCsd::from(buf);
+        debug!("{:?}", ext);
+        Ok(ext)
+    }
+
     pub fn check_rca(&self) -> Result<Rca, CardError> {
         let cmd = send_relative_address();
-        let rca = self.send_cmd(cmd)?.rca();
+        let rca = self
+            .send_cmd(cmd)?
+            .rca()
+            .ok_or(CardError::InvalidResponse)?;
         debug!("{:?}", rca);
-        self.delay.spin_millis(10);
+        self.clock.spin_millis(10);
         Ok(rca)
     }
 
     pub fn check_cid(&self) -> Result<Cid, CardError> {
         let cmd = all_send_cid();
-        let cid = self.send_cmd(cmd)?.cid();
+        let cid = self.send_cmd(cmd)?.cid()?;
         debug!("{:?}", cid);
-        self.delay.spin_millis(10);
+        self.clock.spin_millis(10);
         Ok(cid)
     }
 
-    pub fn check_csd(&self, rca: Rca) -> Result<Csd, CardError> {
+    /// Read the CSD with CMD9, of an eMMC device when `mmc`.
+    pub fn check_csd(&self, rca: Rca, mmc: bool) -> Result<Csd, CardError> {
         let cmd = send_csd(rca.address());
-        let csd = self.send_cmd(cmd)?.csd();
+        let csd = self.send_cmd(cmd)?.csd(mmc)?;
         debug!("{:?}", csd);
-        self.delay.spin_millis(10);
+        self.clock.spin_millis(10);
         Ok(csd)
     }
 
+    /// Read the CID with CMD10. The card must be in stand-by state: a
+    /// selected card is deselected for the command and selected again.
+    pub fn send_cid(&self, rca: Rca) -> Result<Cid, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let selected = self.card_status(rca)?.state() == CurrentState::Transfer;
+        if selected {
+            self.send_cmd(deselect_card())?;
+        }
+        let ret = self
+            .send_cmd(send_cid(rca.address()))
+            .and_then(Response::cid);
+        if selected {
+            self.sel_card(rca)?;
+        }
+        let cid = ret?;
+        debug!("{:?}", cid);
+        Ok(cid)
+    }
+
     pub fn sel_card(&self, rca: Rca) -> Result<(), CardError> {
         let cmd = select_card(rca.address());
         let status = self.send_cmd(cmd)?.card_status();
         debug!("{:?}", status);
-        self.delay.spin_millis(10);
+        self.clock.spin_millis(10);
         Ok(())
     }
 
-    pub fn function_switch(&self, arg: u32) -> Result<(), CardError> {
-        let cmd = switch_function(arg);
-        let status = self.send_cmd(cmd)?.card_status();
+    /// CMD6 with `arg`, returning the switch status read over the data
+    /// lines. In switch mode every group `arg` selects a function for must
+    /// report that function as selected, otherwise the switch failed with
+    /// [`CardError::UnsupportedSpeedMode`].
+    pub fn function_switch(&self, arg: Cmd6Arg) -> Result<SwitchStatus, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let mut buf = [0u8; 64];
+        self.program_transfer(1, 64);
+        let ret = self
+            .send_optional_cmd(switch_function(arg))
+            .and_then(|resp| {
+                let status = resp.card_status();
+                debug!("{status:?}");
+                self.read_data(&mut buf, 1, 64)
+            });
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.recover_single_block(false);
+            return Err(err);
+        }
+        let status = SwitchStatus::from(buf);
+        debug!("{status:?}");
+        if arg.is_switch() {
+            for group in 1..=6 {
+                let function = arg.function(group);
+                if function != 0xF && status.selected(group) != function {
+                    error!("CMD6 group {group} did not switch to function {function}");
+                    return Err(CardError::UnsupportedSpeedMode);
+                }
+            }
+        }
+        self.clock.spin_millis(10);
+        Ok(status)
+    }
+
+    /// Query CMD6 support for high speed, function 1 of group 1, and switch
+    /// to it. Fails with [`CardError::UnsupportedSpeedMode`] when the card
+    /// does not offer or does not take it, and with
+    /// [`CardError::Unsupported`] when the card has no CMD6.
+    pub fn switch_high_speed(&self) -> Result<(), CardError> {
+        let function = SpeedMode::HighSpeed.function();
+        let status = self.function_switch(Cmd6Arg::check().group(1, function))?;
+        if !status.supported(1, function) || status.selected(1) != function {
+            info!("card does not support high speed");
+            return Err(CardError::UnsupportedSpeedMode);
+        }
+        self.function_switch(Cmd6Arg::switch().group(1, function))?;
+        Ok(())
+    }
+
+    /// eMMC SWITCH writing `value` to Ext CSD byte `index`, then wait for the
+    /// device to finish.
+    pub fn mmc_switch(&self, rca: Rca, index: u8, value: u8) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let status = self.send_cmd(mmc_switch(index, value))?.card_status();
         debug!("{:?}", status);
-        self.delay.spin_millis(10);
+        if status.switch_error() {
+            return Err(CardError::Unsupported);
+        }
+        self.wait_programmed(rca, Timeout::WaitTranState.into())
+    }
+
+    /// Read the SD Status with ACMD13 over the data lines.
+    pub fn read_sd_status(&self, rca: Rca) -> Result<SdStatus, CardError> {
+        let mut buf = [0u8; 64];
+        self.read_app_register(rca, sd_status(), &mut buf)?;
+        let status = SdStatus::from(buf);
+        debug!("{:?}", status);
+        Ok(status)
+    }
+
+    /// Read the undecoded SD Status with ACMD13.
+    pub fn read_sd_status_raw(&self, rca: Rca, buf: &mut [u8; 64]) -> Result<(), CardError> {
+        self.read_app_register(rca, sd_status(), buf)
+    }
+
+    /// Read the SCR with ACMD51.
+    pub fn read_scr(&self, rca: Rca) -> Result<Scr, CardError> {
+        let mut buf = [0u8; 8];
+        self.read_app_register(rca, send_scr(), &mut buf)?;
+        let scr = Some(Scr::from(buf))
+            .filter(Scr::is_valid)
+            .ok_or(CardError::InvalidResponse)?;
+        debug!("{:?}", scr);
+        Ok(scr)
+    }
+
+    /// Read the undecoded SCR with ACMD51.
+    pub fn read_scr_raw(&self, rca: Rca, buf: &mut [u8; 8]) -> Result<(), CardError> {
+        self.read_app_register(rca, send_scr(), buf)
+    }
+
+    /// Run the application command `cmd` and read the register it sends
+    /// over the data lines into `buf`, a single block of its length.
+    fn read_app_register(&self, rca: Rca, cmd: Command, buf: &mut [u8]) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let len = buf.len() as u32;
+        let ret = self
+            .send_cmd(app_cmd(rca.address()))
+            .and_then(|_| {
+                self.program_transfer(1, len);
+                self.send_cmd(cmd)
+            })
+            .and_then(|resp| {
+                let status = resp.card_status();
+                debug!("{status:?}");
+                self.read_data(buf, 1, len)
+            });
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.recover_single_block(false);
+        }
+        ret
+    }
+
+    /// Switch eMMC device and controller to a 1, 4 or 8-bit bus through the
+    /// Ext CSD BUS_WIDTH byte.
+    pub fn set_mmc_bus(&self, rca: Rca, width: BusWidth) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let (value, ctype) = match width {
+            BusWidth::One => (0, 0),
+            BusWidth::Four => (1, 1),
+            BusWidth::Eight => (2, 0x0001_0000),
+            BusWidth::Unknown => return Err(CardError::Unsupported),
+        };
+        self.mmc_switch(rca, EXT_CSD_BUS_WIDTH, value)?;
+        self.bus.write32(REG_CTYPE, ctype);
+        self.clock.spin_millis(10);
         Ok(())
     }
 
-    pub fn set_bus(&self, rca: Rca) -> Result<(), CardError> {
+    /// Switch card and controller to a 1 or 4-bit bus with ACMD6.
+    pub fn set_bus(&self, rca: Rca, width: BusWidth) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let (arg, ctype) = match width {
+            BusWidth::One => (0, 0),
+            BusWidth::Four => (2, 1),
+            _ => return Err(CardError::Unsupported),
+        };
         self.send_cmd(app_cmd(rca.address()))?;
-        let status = self.send_cmd(set_bus_width(2))?.card_status();
+        let status = self.send_cmd(set_bus_width(arg))?.card_status();
+        debug!("{:?}", status);
+        self.bus.write32(REG_CTYPE, ctype);
+        self.clock.spin_millis(10);
+        Ok(())
+    }
+
+    pub fn card_status(&self, rca: Rca) -> Result<CardStatus, CardError> {
+        Ok(self
+            .send_cmd(send_status(rca.address(), false))?
+            .card_status())
+    }
+
+    pub fn send_status(&self, rca: Rca, format: StatusFormat) -> Result<Status, CardError> {
+        match format {
+            StatusFormat::Card => self.card_status(rca).map(Status::Card),
+            StatusFormat::Task => match self.send_cmd(send_task_status(rca.address()))? {
+                Response::R48(tasks) => Ok(Status::Task(tasks)),
+                _ => Err(CardError::InvalidResponse),
+            },
+        }
+    }
+
+    /// Send HPI as CMD12 or CMD13, as the device asks for, then wait up to
+    /// `millis` for it to leave the interrupted operation.
+    pub fn hpi(&self, rca: Rca, use_cmd12: bool, millis: u64) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        let cmd = if use_cmd12 {
+            stop_transmission_hpi(rca.address())
+        } else {
+            send_status(rca.address(), true)
+        };
+        let status = self.send_cmd(cmd)?.card_status();
+        debug!("{:?}", status);
+        if self.wait_for(millis, || {
+            self.card_status(rca)
+                .is_ok_and(|status| status.state() == CurrentState::Transfer)
+        }) {
+            Ok(())
+        } else {
+            Err(Timeout::WaitTranState.into())
+        }
+    }
+
+    pub fn write_protect(&self, addr: u32, protect: bool) -> Result<(), CardError> {
+        let cmd = if protect {
+            set_write_prot(addr)
+        } else {
+            clr_write_prot(addr)
+        };
+        let status = self.send_optional_cmd(cmd)?.card_status();
+        debug!("{:?}", status);
+        Ok(())
+    }
+
+    pub fn send_write_protect(&self, addr: u32) -> Result<u32, CardError> {
+        let _guard = LockGuard::new(self.lock);
+        self.program_transfer(1, 4);
+        let mut buf = [0u8; 4];
+        let ret = self
+            .send_optional_cmd(send_write_prot(addr))
+            .and_then(|resp| {
+                let status = resp.card_status();
+                debug!("{status:?}");
+                self.read_data(&mut buf, 1, 4)
+            });
+        if let Err(err) = ret {
+            debug!("{err:?}");
+            self.recover_single_block(false);
+        }
+        ret.map(|_| u32::from_be_bytes(buf))
+    }
+
+    /// Erase the blocks from card address `start` to `end`, both included,
+    /// with CMD32, CMD33 and CMD38, waiting up to `timeout_ms` for the card
+    /// to finish.
+    pub fn erase(&self, start: u32, end: u32, timeout_ms: u64) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
+        for cmd in [erase_wr_blk_start(start), erase_wr_blk_end(end)] {
+            let status = self.send_cmd(cmd)?.card_status();
+            debug!("{:?}", status);
+        }
+        let status = self.send_cmd_busy(erase(), timeout_ms)?.card_status();
         debug!("{:?}", status);
-        self.delay.spin_millis(10);
         Ok(())
     }
 
+    /// Send CMD12 with stop_abort_cmd. While the CIU reports a hardware lock
+    /// error the command is retried with a doubling delay from 10 us; after
+    /// `hle_retries` failed attempts the controller is reset instead.
     pub fn stop_transmission_ops(&self) -> Result<(), CardError> {
+        let _guard = LockGuard::new(self.lock);
         let cmd = stop_transmission();
+        let mut attempt = 0;
         loop {
             self.wait_for_cmd_line()?;
-            write_reg::<u32>(self.sdio_base, REG_RINTSTS, InterruptMask::all().bits());
-            write_reg::<u32>(self.sdio_base, REG_CMDARG, cmd.arg());
-            write_reg::<u32>(self.sdio_base, REG_CMD, cmd.to_cmd());
-            if read_reg::<u32>(self.sdio_base, REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
+            self.clear_status(InterruptMask::all().bits());
+            self.bus.write32(REG_CMDARG, cmd.arg());
+            self.bus.write32(REG_CMD, cmd.to_cmd());
+            if self.bus.read32(REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
                 debug!("send {:?}", CmdMask::from_bits(cmd.to_cmd()).unwrap());
                 break;
             }
+            if attempt >= self.hle_retries {
+                error!("CIU still locked after {attempt} retries, reset controller");
+                let ctrl = self.bus.read32(REG_CTRL);
+                let mask = ControlMask::controller_reset.bits();
+                self.bus.write32(REG_CTRL, ctrl | mask);
+                self.wait_reset(mask)?;
+                return Err(Interrupt::HardwareLock.into());
+            }
+            self.clock.spin_micros(10 << attempt.min(10));
+            attempt += 1;
         }
-        let status = Response::R48(read_reg(self.sdio_base, REG_RESP0)).card_status();
+        let status = Response::R48(self.bus.read32(REG_RESP0)).card_status();
         debug!("{status:?}");
         self.wait_for_cmd_done()?;
         Ok(())
     }
 
+    pub fn snapshot(&self) -> RegisterSnapshot {
+        let reg = |offset| self.bus.read32(offset);
+        RegisterSnapshot {
+            ctrl: reg(REG_CTRL),
+            pwren: reg(REG_PWREN),
+            clkdiv: reg(REG_CLKDIV),
+            clkena: reg(REG_CLKENA),
+            ctype: reg(REG_CTYPE),
+            cmd: reg(REG_CMD),
+            cmdarg: reg(REG_CMDARG),
+            resp0: reg(REG_RESP0),
+            rintsts: reg(REG_RINTSTS),
+            status: reg(REG_STATUS),
+            bytcnt: reg(REG_BYTCNT),
+        }
+    }
+
     fn wait_for<F: FnMut() -> bool>(&self, millis: u64, mut f: F) -> bool {
-        let count_down = MillisCountDown::new(millis, self.ticker);
+        let count_down = self.clock.count_down(millis);
         loop {
             if count_down.timeout() {
                 return false;
@@ -308,3 +1604,150 @@ impl MmcOperate {
         true
     }
 }
+
+/// Tell apart the two hardware data timeouts: `hto` when the FIFO was not
+/// serviced in time by the host, `drto` when the card sent no data.
+fn check_data_timeout(mask: u32) -> Result<(), CardError> {
+    if mask & InterruptMask::hto.bits() != 0 {
+        Err(CardError::HostStarvationTimeout)
+    } else if mask & InterruptMask::drto.bits() != 0 {
+        Err(CardError::DataReadTimeout)
+    } else {
+        Ok(())
+    }
+}
+
+/// Copy the FIFO entry `word` to the start of `buf`, stopping at its end,
+/// and return the number of bytes copied.
+fn unpack_fifo_word(buf: &mut [u8], word: u32) -> usize {
+    let bytes = word.to_le_bytes();
+    let len = buf.len().min(bytes.len());
+    buf[..len].copy_from_slice(&bytes[..len]);
+    len
+}
+
+/// FIFO entry holding the first bytes of `buf`, zero padded past its end,
+/// and the number of bytes it takes.
+fn pack_fifo_word(buf: &[u8]) -> (u32, usize) {
+    let mut bytes = [0u8; 4];
+    let len = buf.len().min(bytes.len());
+    bytes[..len].copy_from_slice(&buf[..len]);
+    (u32::from_le_bytes(bytes), len)
+}
+
+/// Bus address of identity-mapped memory at `addr`, which the 32-bit IDMAC
+/// needs word aligned and below 4 GiB.
+fn dma_address(addr: usize) -> Result<u32, CardError> {
+    u32::try_from(addr)
+        .ok()
+        .filter(|addr| addr % 4 == 0)
+        .ok_or(CardError::DmaAddress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MockBus;
+    use crate::clock::MockClock;
+
+    /// Driver on a mock bus with the card clock running.
+    fn mock() -> MmcOperate<MockBus, MockClock> {
+        let ops = MmcOperate::with_bus(MockBus::default(), MockClock);
+        ops.bus.set(REG_CLKENA, ClkEnaMask::cclk_enable.bits());
+        ops
+    }
+
+    /// CMD13 completing with `status` raised next to command done.
+    fn send_status_with(status: InterruptMask) -> Result<Response, CardError> {
+        let ops = mock();
+        ops.bus
+            .set(REG_RINTSTS, (InterruptMask::cmd | status).bits());
+        ops.send_cmd(send_status(1, false))
+    }
+
+    #[test]
+    fn send_cmd_writes_argument_and_command() {
+        let ops = mock();
+        ops.bus.set(REG_RINTSTS, InterruptMask::cmd.bits());
+        ops.bus.set(REG_RESP0, 4 << 9);
+        // the previous command still loading on the first poll
+        ops.bus.script(REG_CMD, &[CmdMask::start_cmd.bits()]);
+        let cmd = send_status(1, false);
+        let resp = ops.send_cmd(cmd).unwrap();
+        assert_eq!(resp.card_status().state(), CurrentState::Transfer);
+        assert_eq!(ops.bus.reads(REG_CMD), 2);
+        assert_eq!(ops.bus.writes(REG_CMDARG), [1 << 16]);
+        assert_eq!(ops.bus.writes(REG_CMD), [cmd.to_cmd()]);
+    }
+
+    #[test]
+    fn send_cmd_response_timeout() {
+        assert!(matches!(
+            send_status_with(InterruptMask::rto),
+            Err(CardError::InterruptErr(Interrupt::ResponseTimeout))
+        ));
+    }
+
+    #[test]
+    fn send_cmd_response_error() {
+        assert!(matches!(
+            send_status_with(InterruptMask::re),
+            Err(CardError::InterruptErr(Interrupt::ResponseErr))
+        ));
+    }
+
+    #[test]
+    fn send_cmd_response_crc() {
+        assert!(matches!(
+            send_status_with(InterruptMask::rcrc),
+            Err(CardError::InterruptErr(Interrupt::ResponseCrc))
+        ));
+    }
+
+    #[test]
+    fn send_cmd_r2_crc_quirk() {
+        let ops = mock().with_r2_crc_quirk(true);
+        let status = InterruptMask::cmd | InterruptMask::rcrc;
+        ops.bus.set(REG_RINTSTS, status.bits());
+        assert!(ops.send_cmd(send_cid(1)).is_ok());
+        assert!(ops.take_crc_suspect());
+        // only R2 responses are let through
+        assert!(ops.send_cmd(send_status(1, false)).is_err());
+    }
+
+    #[test]
+    fn fifo_read_past_buffer_is_overrun() {
+        let ops = mock().with_fifo_batch(16);
+        let done = InterruptMask::rxdr | InterruptMask::dto;
+        ops.bus.set(REG_RINTSTS, done.bits());
+        // eight entries waiting for a six byte buffer
+        ops.bus.set(REG_STATUS, 8 << FIFO_COUNT_SHIFT);
+        ops.bus
+            .script(REG_DATA, &[0x4433_2211, 0x8877_6655, 0xFFFF_FFFF]);
+        let mut storage = [0xAAu8; 12];
+        let ret = ops.read_fifo(&mut storage[..6], true);
+        assert!(matches!(ret, Err(CardError::DataOverrun)));
+        assert_eq!(storage[..6], [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        assert_eq!(storage[6..], [0xAA; 6]);
+        assert_eq!(ops.bus.reads(REG_DATA), 2);
+    }
+
+    #[test]
+    fn fifo_word_stops_at_buffer_end() {
+        let mut buf = [0u8; 6];
+        assert_eq!(unpack_fifo_word(&mut buf, 0x4433_2211), 4);
+        assert_eq!(unpack_fifo_word(&mut buf[4..], 0x8877_6655), 2);
+        assert_eq!(buf, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        // an entry the card sends past the end is never written anywhere
+        assert_eq!(unpack_fifo_word(&mut buf[6..], 0xFFFF_FFFF), 0);
+        assert_eq!(buf[5], 0x66);
+    }
+
+    #[test]
+    fn fifo_word_pads_the_tail() {
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        assert_eq!(pack_fifo_word(&data), (0x4433_2211, 4));
+        assert_eq!(pack_fifo_word(&data[4..]), (0x0000_6655, 2));
+        assert_eq!(pack_fifo_word(&[]), (0, 0));
+    }
+}
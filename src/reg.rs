@@ -24,7 +24,7 @@ reg_map!(
     REG_CDETECT 0x050,
     REG_WRTPRT 0x054,
     REG_GPIO 0x058,
-    REG_TCMCNT 0x05C,
+    REG_TCBCNT 0x05C,
     REG_TBBCNT 0x060,
     REG_DEBNCE 0x064,
     REG_USRID 0x068,
@@ -40,9 +40,27 @@ reg_map!(
     REG_DSCADDR 0x94,
     REG_BUFADDR 0x98,
     REG_CARD_THR_CTL 0x100,
+    REG_UHS_REG_EXT 0x108,
     REG_DATA 0x200
 );
 pub const DATA_TMOUT_DEFUALT: usize = 0xFFFFFF << 8;
+pub const CLK_SMPL_PHASE_MAX: u8 = 0x7F;
+/// OCR sent with CMD1: sector addressing, 2.7-3.6 V and 1.70-1.95 V
+pub const MMC_HOST_OCR: u32 = 0x40FF_8080;
+/// OCR sent with CMD1 on a 1.8 V only bus: sector addressing, 1.70-1.95 V
+pub const MMC_HOST_OCR_1V8: u32 = 0x4000_0080;
+/// RCA the driver assigns to an eMMC device
+pub const MMC_RCA: u16 = 1;
+/// Bytes per IDMAC descriptor buffer, within the 13-bit size field
+pub const IDMAC_DESC_BUF_LEN: u32 = 0x1000;
+/// Position of `STATUS.fifo_count`
+pub const FIFO_COUNT_SHIFT: u32 = 17;
+/// Position of `FIFOTH.RX_WMARK`
+pub const RX_WMARK_SHIFT: u32 = 16;
+/// Position of `FIFOTH.DW_DMA_Multiple_Transaction_Size`
+pub const DMA_MTS_SHIFT: u32 = 28;
+/// Burst sizes in FIFO entries selected by `DW_DMA_Multiple_Transaction_Size`
+pub const DMA_BURSTS: [u16; 8] = [1, 4, 8, 16, 32, 64, 128, 256];
 // pub const BLKSIZ_DEFAULT: usize = 0x200;
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -124,6 +142,31 @@ bitflags! {
         const fifo_rx_watermark= 0b1;
     }
 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FifoThMask: u32{
+        const dma_mts = 0b111 << 28;
+        const rx_wmark = 0xFFF << 16;
+        const tx_wmark = 0xFFF;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ClkEnaMask: u32{
+        const cclk_low_power = 0b1 << 16;
+        const cclk_enable = 0b1;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UhsMask: u32{
+        const ddr_reg = 0b1 << 16;
+        const volt_reg = 0b1;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UhsExtMask: u32{
+        const clk_drv_phase_ctrl = 0x7F << 23;
+        const clk_smpl_phase_ctrl = 0x7F << 16;
+    }
+
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct HardConfig: u32{
         const card_type = 0x1;
@@ -141,6 +184,24 @@ bitflags! {
         const fifo_depth = 0x1F << 27;
     }
 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BusModeMask: u32{
+        const de = 0b1 << 7;
+        const fb = 0b1 << 1;
+        const swr = 0b1;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DescFlags: u32{
+        const own = 0b1 << 31;
+        const ces = 0b1 << 30;
+        const er = 0b1 << 5;
+        const ch = 0b1 << 4;
+        const fs = 0b1 << 3;
+        const ld = 0b1 << 2;
+        const dic = 0b1 << 1;
+    }
+
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct DmaIntEn: u32{
         const ti = 0b1;
@@ -166,6 +227,61 @@ bitflags! {
     }
 }
 
+/// IDMAC descriptor in 32-bit address mode.
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Default)]
+pub struct IdmacDesc {
+    pub des0: u32,
+    pub des1: u32,
+    pub des2: u32,
+    pub des3: u32,
+}
+
+impl IdmacDesc {
+    pub const fn new() -> Self {
+        Self {
+            des0: 0,
+            des1: 0,
+            des2: 0,
+            des3: 0,
+        }
+    }
+}
+
+/// Controller registers captured for post-mortem diagnostics.
+#[derive(Clone, Copy, Default)]
+pub struct RegisterSnapshot {
+    pub ctrl: u32,
+    pub pwren: u32,
+    pub clkdiv: u32,
+    pub clkena: u32,
+    pub ctype: u32,
+    pub cmd: u32,
+    pub cmdarg: u32,
+    pub resp0: u32,
+    pub rintsts: u32,
+    pub status: u32,
+    pub bytcnt: u32,
+}
+
+impl Debug for RegisterSnapshot {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Register Snapshot")
+            .field("CTRL", &format_args!("{:#010x}", self.ctrl))
+            .field("PWREN", &format_args!("{:#010x}", self.pwren))
+            .field("CLKDIV", &format_args!("{:#010x}", self.clkdiv))
+            .field("CLKENA", &format_args!("{:#010x}", self.clkena))
+            .field("CTYPE", &format_args!("{:#010x}", self.ctype))
+            .field("CMD", &format_args!("{:#010x}", self.cmd))
+            .field("CMDARG", &format_args!("{:#010x}", self.cmdarg))
+            .field("RESP0", &format_args!("{:#010x}", self.resp0))
+            .field("RINTSTS", &format_args!("{:#010x}", self.rintsts))
+            .field("STATUS", &format_args!("{:#010x}", self.status))
+            .field("BYTCNT", &format_args!("{:#010x}", self.bytcnt))
+            .finish()
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct HardConf(pub u32);
 impl From<u32> for HardConf {
@@ -1,5 +1,7 @@
 use core::{fmt::Debug, str};
 
+use crate::crc::crc7;
+
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SDSpecVersion {
@@ -24,6 +26,84 @@ pub enum BusWidth {
     Eight = 8,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SpeedMode {
+    /// Default speed / SDR12
+    Default,
+    /// High speed / SDR25
+    HighSpeed,
+    Sdr50,
+    Sdr104,
+    Ddr50,
+}
+
+impl SpeedMode {
+    /// SDR50, SDR104 and DDR50 are subject to the CMD6 current limit.
+    pub fn is_uhs(&self) -> bool {
+        matches!(
+            self,
+            SpeedMode::Sdr50 | SpeedMode::Sdr104 | SpeedMode::Ddr50
+        )
+    }
+
+    /// CMD6 function group 1 value selecting this mode.
+    pub fn function(&self) -> u8 {
+        match self {
+            SpeedMode::Default => 0,
+            SpeedMode::HighSpeed => 1,
+            SpeedMode::Sdr50 => 2,
+            SpeedMode::Sdr104 => 3,
+            SpeedMode::Ddr50 => 4,
+        }
+    }
+}
+
+/// Card output driver strength, CMD6 function group 3.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DriverStrength {
+    /// 50 ohm, the default
+    TypeB,
+    /// 33 ohm
+    TypeA,
+    /// 66 ohm
+    TypeC,
+    /// 100 ohm
+    TypeD,
+}
+
+impl DriverStrength {
+    /// CMD6 function group 3 value selecting this strength.
+    pub fn function(&self) -> u8 {
+        match self {
+            DriverStrength::TypeB => 0,
+            DriverStrength::TypeA => 1,
+            DriverStrength::TypeC => 2,
+            DriverStrength::TypeD => 3,
+        }
+    }
+}
+
+/// Card peak current limit, CMD6 function group 4.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CurrentLimit {
+    Ma200,
+    Ma400,
+    Ma600,
+    Ma800,
+}
+
+impl CurrentLimit {
+    /// CMD6 function group 4 value selecting this limit.
+    pub fn function(&self) -> u8 {
+        match self {
+            CurrentLimit::Ma200 => 0,
+            CurrentLimit::Ma400 => 1,
+            CurrentLimit::Ma600 => 2,
+            CurrentLimit::Ma800 => 3,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum BlockSize {
     #[non_exhaustive]
@@ -155,6 +235,10 @@ impl Ocr {
     pub const fn new() -> Self {
         Self(0)
     }
+    /// Reserved bits 14:8 and 6:0 must read as zero.
+    pub fn is_valid(&self) -> bool {
+        self.0 & 0x7F7F == 0
+    }
     pub fn is_busy(&self) -> bool {
         self.0 & 0x8000_0000 == 0
     }
@@ -218,7 +302,19 @@ impl Debug for Ocr {
             .finish()
     }
 }
-#[derive(Copy, Clone, Default)]
+/// The CID as returned in R2, bit 127 first. The controller drops the start
+/// bits, so bit 0 is the end bit and the CRC7 sits in bits 7:1:
+///
+/// | field | bits    |
+/// |-------|---------|
+/// | MID   | 127:120 |
+/// | OID   | 119:104 |
+/// | PNM   | 103:64  |
+/// | PRV   | 63:56   |
+/// | PSN   | 55:24   |
+/// | MDT   | 19:8    |
+/// | CRC   | 7:1     |
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub struct Cid {
     inner: u128,
     bytes: [u8; 16],
@@ -252,34 +348,47 @@ impl Cid {
             bytes: [0; 16],
         }
     }
+    /// The CRC7 in the last byte must match the preceding 120 bits.
+    pub fn is_valid(&self) -> bool {
+        crc7(&self.bytes[..15]) == self.crc7()
+    }
     pub fn manufacturer_id(&self) -> u8 {
         self.bytes[0]
     }
-    #[allow(unused)]
     pub fn crc7(&self) -> u8 {
         (self.bytes[15] >> 1) & 0x7F
     }
 
-    pub fn oem_id(&self) -> &str {
-        str::from_utf8(&self.bytes[1..3]).unwrap_or(&"<ERR>")
+    /// OID, normally two ASCII characters.
+    pub fn oem_id(&self) -> [u8; 2] {
+        [self.bytes[1], self.bytes[2]]
     }
 
-    pub fn product_name(&self) -> &str {
-        str::from_utf8(&self.bytes[3..8]).unwrap_or(&"<ERR>")
+    /// PNM, normally five ASCII characters.
+    pub fn product_name(&self) -> [u8; 5] {
+        [
+            self.bytes[3],
+            self.bytes[4],
+            self.bytes[5],
+            self.bytes[6],
+            self.bytes[7],
+        ]
     }
 
-    pub fn product_revision(&self) -> u8 {
-        self.bytes[8]
+    /// PRV as (major, minor), one BCD digit each.
+    pub fn product_revision(&self) -> (u8, u8) {
+        (self.bytes[8] >> 4, self.bytes[8] & 0xF)
     }
 
-    pub fn serial(&self) -> u32 {
+    pub fn serial_number(&self) -> u32 {
         (self.inner >> 24) as u32
     }
 
-    pub fn manufacturing_date(&self) -> (u8, u16) {
+    /// MDT as (year, month), the year counting from 2000.
+    pub fn manufacturing_date(&self) -> (u16, u8) {
         (
-            (self.inner >> 8) as u8 & 0xF,             // Month
-            ((self.inner >> 12) as u16 & 0xFF) + 2000, // Year
+            ((self.inner >> 12) as u16 & 0xFF) + 2000,
+            (self.inner >> 8) as u8 & 0xF,
         )
     }
 }
@@ -288,10 +397,13 @@ impl Debug for Cid {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CID: Card Identification")
             .field("Manufacturer ID", &self.manufacturer_id())
-            .field("OEM ID", &self.oem_id())
-            .field("Product Name", &self.product_name())
+            .field("OEM ID", &str::from_utf8(&self.oem_id()).unwrap_or("<ERR>"))
+            .field(
+                "Product Name",
+                &str::from_utf8(&self.product_name()).unwrap_or("<ERR>"),
+            )
             .field("Product Revision", &self.product_revision())
-            .field("Product Serial Number", &self.serial())
+            .field("Product Serial Number", &self.serial_number())
             .field("Manufacturing Date", &self.manufacturing_date())
             .finish()
     }
@@ -312,6 +424,16 @@ impl Csd {
     pub const fn new() -> Self {
         Self(0)
     }
+    /// The CRC7 must match and CSD_STRUCTURE must not be the value 3, which
+    /// is reserved on SD cards. eMMC devices use it, check only the CRC7.
+    pub fn is_valid(&self) -> bool {
+        self.crc_matches() && self.version() != 3
+    }
+    /// The CRC7 in the last byte matches the preceding 120 bits.
+    pub fn crc_matches(&self) -> bool {
+        let bytes = self.0.to_be_bytes();
+        crc7(&bytes[..15]) == (bytes[15] >> 1) & 0x7F
+    }
     pub fn version(&self) -> u8 {
         (self.0 >> 126) as u8 & 3
     }
@@ -385,6 +507,28 @@ impl Csd {
         self.block_count() * block_size_bytes
     }
 
+    /// R2W_FACTOR: typical write time as a power-of-two multiple of the read
+    /// access time.
+    pub fn r2w_factor(&self) -> u8 {
+        (self.0 >> 26) as u8 & 0x7
+    }
+
+    /// WP_GRP_ENABLE: groups of blocks can be write protected with CMD28.
+    pub fn wp_group_enabled(&self) -> bool {
+        (self.0 >> 31) & 1 != 0
+    }
+
+    /// PERM_WRITE_PROTECT: the whole card is write protected for good.
+    pub fn perm_write_protect(&self) -> bool {
+        (self.0 >> 13) & 1 != 0
+    }
+
+    /// TMP_WRITE_PROTECT: the whole card is write protected until the bit
+    /// is cleared again.
+    pub fn tmp_write_protect(&self) -> bool {
+        (self.0 >> 12) & 1 != 0
+    }
+
     pub fn erase_size_blocks(&self) -> u32 {
         if (self.0 >> 46) & 1 == 1 {
             // ERASE_BLK_EN
@@ -409,9 +553,29 @@ impl Debug for Csd {
             .field("Read I (@max VDD)", &self.read_current_maximum_vdd())
             .field("Write I (@max VDD)", &self.write_current_maximum_vdd())
             .field("Erase Size (Blocks)", &self.erase_size_blocks())
+            .field("Read to Write Factor", &(1 << self.r2w_factor()))
+            .field("Permanent Write Protect", &self.perm_write_protect())
+            .field("Temporary Write Protect", &self.tmp_write_protect())
             .finish()
     }
 }
+/// What CMD13 is asked to return.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum StatusFormat {
+    /// R1 card status
+    #[default]
+    Card,
+    /// Command queue task status, bit `n` set when task `n` is ready
+    Task,
+}
+
+/// CMD13 response, decoded according to the requested [`StatusFormat`].
+#[derive(Copy, Clone, Debug)]
+pub enum Status {
+    Card(CardStatus),
+    Task(u32),
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct CardStatus(u32);
 impl From<u32> for CardStatus {
@@ -429,6 +593,11 @@ impl CardStatus {
         self.0 & 0x40 != 0
     }
 
+    /// eMMC only: the last SWITCH was rejected.
+    pub fn switch_error(&self) -> bool {
+        self.0 & 0x80 != 0
+    }
+
     pub fn ake_seq_error(&self) -> bool {
         self.0 & 0x8 != 0
     }
@@ -551,6 +720,10 @@ impl Rca {
     pub const fn new() -> Self {
         Self(0)
     }
+    /// RCA 0 is reserved for deselecting all cards.
+    pub fn is_valid(&self) -> bool {
+        self.address() != 0
+    }
     pub fn address(&self) -> u16 {
         (self.0 >> 16) as u16
     }
@@ -569,6 +742,53 @@ impl Debug for Rca {
     }
 }
 
+/// The 512-bit switch function status returned on the data lines by CMD6.
+#[derive(Copy, Clone)]
+pub struct SwitchStatus([u8; 64]);
+
+impl From<[u8; 64]> for SwitchStatus {
+    fn from(value: [u8; 64]) -> Self {
+        Self(value)
+    }
+}
+
+impl SwitchStatus {
+    /// Maximum current consumption with the selected functions, 0 on error.
+    pub fn max_current_ma(&self) -> u16 {
+        u16::from_be_bytes([self.0[0], self.0[1]])
+    }
+
+    /// Whether the card supports `function` of `group`, 1 to 6.
+    pub fn supported(&self, group: u8, function: u8) -> bool {
+        let at = 14 - 2 * group as usize;
+        let bits = u16::from_be_bytes([self.0[at], self.0[at + 1]]);
+        function < 16 && bits & (1 << function) != 0
+    }
+
+    /// Function of `group` that is, or would be, selected; 0xF when the
+    /// requested one cannot be switched to.
+    pub fn selected(&self, group: u8) -> u8 {
+        let nibble = 6 - group as usize;
+        let byte = self.0[14 + nibble / 2];
+        if nibble.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0xF
+        }
+    }
+}
+
+impl Debug for SwitchStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Switch Function Status")
+            .field("Maximum Current (mA)", &self.max_current_ma())
+            .field("Access Mode", &self.selected(1))
+            .field("Driver Strength", &self.selected(3))
+            .field("Current Limit", &self.selected(4))
+            .finish()
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct Scr(u64);
 impl From<(u32, u32)> for Scr {
@@ -578,7 +798,23 @@ impl From<(u32, u32)> for Scr {
     }
 }
 
+/// The 8 bytes sent by ACMD51, most significant first.
+impl From<[u8; 8]> for Scr {
+    fn from(value: [u8; 8]) -> Self {
+        Self(u64::from_be_bytes(value))
+    }
+}
+
 impl Scr {
+    /// SCR_STRUCTURE, 0 for the only version defined.
+    pub fn structure(&self) -> u8 {
+        (self.0 >> 60) as u8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.structure() == 0
+    }
+
     pub fn version(&self) -> SDSpecVersion {
         let spec = (self.0 >> 56) & 0xF;
         let spec3 = (self.0 >> 47) & 1;
@@ -612,14 +848,27 @@ impl Scr {
     pub fn bus_width_four(&self) -> bool {
         (self.0 >> 50) & 1 != 0
     }
+
+    /// CMD_SUPPORT bit 1: SET_BLOCK_COUNT (CMD23) is supported.
+    pub fn cmd23_supported(&self) -> bool {
+        (self.0 >> 33) & 1 != 0
+    }
+
+    /// DATA_STAT_AFTER_ERASE: the value every bit reads as after an erase.
+    pub fn data_stat_after_erase(&self) -> u8 {
+        ((self.0 >> 55) & 1) as u8
+    }
 }
 
 impl Debug for Scr {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SCR: SD CARD Configuration Register")
+            .field("Structure", &self.structure())
             .field("Version", &self.version())
             .field("1-bit width", &self.bus_width_one())
             .field("4-bit width", &self.bus_width_four())
+            .field("Data after erase", &self.data_stat_after_erase())
+            .field("CMD23", &self.cmd23_supported())
             .finish()
     }
 }
@@ -636,6 +885,10 @@ impl Cic {
     pub const fn new() -> Self {
         Self(0)
     }
+    /// Reserved bits 31:14 must read as zero.
+    pub fn is_valid(&self) -> bool {
+        self.0 & 0xFFFF_C000 == 0
+    }
     pub fn voltage_accepted(&self) -> u8 {
         (self.0 >> 8) as u8
     }
@@ -645,6 +898,15 @@ impl Cic {
     }
 }
 
+impl Debug for Cic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CIC: Card Interface Condition")
+            .field("Voltage Accepted", &self.voltage_accepted())
+            .field("Check Pattern", &self.pattern())
+            .finish()
+    }
+}
+
 #[derive(Clone, Copy, Default)]
 pub struct SdStatus {
     inner: [u32; 16],
@@ -656,6 +918,18 @@ impl From<[u32; 16]> for SdStatus {
     }
 }
 
+/// The status block as read from the data lines, most significant byte
+/// first.
+impl From<[u8; 64]> for SdStatus {
+    fn from(value: [u8; 64]) -> Self {
+        let mut inner = [0u32; 16];
+        for (i, word) in value.chunks_exact(4).enumerate() {
+            inner[15 - i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        Self { inner }
+    }
+}
+
 impl SdStatus {
     pub fn bus_width(&self) -> BusWidth {
         match (self.inner[15] >> 30) & 3 {
@@ -690,23 +964,63 @@ impl SdStatus {
     }
 
     pub fn erase_size(&self) -> u16 {
-        (self.inner[13] & 0xFF) as u16 | ((self.inner[12] >> 24) & 0xFF) as u16
+        ((self.inner[13] & 0xFF) as u16) << 8 | ((self.inner[12] >> 24) & 0xFF) as u16
     }
 
     pub fn erase_timeout(&self) -> u8 {
         (self.inner[12] >> 18) as u8 & 0x3F
     }
 
+    /// ERASE_OFFSET in seconds, added once to every erase timeout.
+    pub fn erase_offset(&self) -> u8 {
+        (self.inner[12] >> 16) as u8 & 0x3
+    }
+
+    /// AU_SIZE in bytes, `None` when not defined.
+    pub fn au_size_bytes(&self) -> Option<u32> {
+        let kib = match self.allocation_unit_size() {
+            0 => return None,
+            code @ 1..=0xA => 8 << code,
+            0xB => 12 * 1024,
+            0xC => 16 * 1024,
+            0xD => 24 * 1024,
+            0xE => 32 * 1024,
+            _ => 64 * 1024,
+        };
+        Some(kib * 1024)
+    }
+
+    /// Time the card may stay busy erasing `blocks` 512-byte blocks:
+    /// ERASE_TIMEOUT per ERASE_SIZE AUs, for every AU touched, plus
+    /// ERASE_OFFSET. `None` when the card does not give these fields.
+    pub fn erase_timeout_ms(&self, blocks: u64) -> Option<u64> {
+        let au = self.au_size_bytes()?;
+        if self.erase_size() == 0 || self.erase_timeout() == 0 {
+            return None;
+        }
+        let units = (blocks * 512).div_ceil(u64::from(au));
+        let per_units = u64::from(self.erase_timeout()) * 1000;
+        Some(
+            per_units * units / u64::from(self.erase_size())
+                + u64::from(self.erase_offset()) * 1000,
+        )
+    }
+
+    /// UHS_SPEED_GRADE: 0, or 1 and 3 for U1 and U3.
+    pub fn uhs_speed_grade(&self) -> u8 {
+        (self.inner[12] >> 12) as u8 & 0xF
+    }
+
     pub fn video_speed_class(&self) -> u8 {
-        (self.inner[11] & 0xFF) as u8
+        (self.inner[12] & 0xFF) as u8
     }
 
     pub fn app_perf_class(&self) -> u8 {
-        (self.inner[9] >> 16) as u8 & 0xF
+        (self.inner[10] >> 16) as u8 & 0xF
     }
 
     pub fn discard_support(&self) -> bool {
-        self.inner[8] & 0x0200_0000 != 0
+        self.inner[9] & 0x0200_0000 != 0
     }
 }
 impl Debug for SdStatus {
@@ -717,6 +1031,7 @@ impl Debug for SdStatus {
             .field("SD Memory Card Type", &self.sd_memory_card_type())
             .field("Protected Area Size (B)", &self.protected_area_size())
             .field("Speed Class", &self.speed_class())
+            .field("UHS Speed Grade", &self.uhs_speed_grade())
             .field("Video Speed Class", &self.video_speed_class())
             .field("Application Performance Class", &self.app_perf_class())
             .field("Move Performance (MB/s)", &self.move_performance())
@@ -727,3 +1042,274 @@ impl Debug for SdStatus {
             .finish()
     }
 }
+
+/// eMMC pre-EOL information, Ext CSD byte 267.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PreEolInfo {
+    Undefined,
+    Normal,
+    /// 80% of the reserved blocks are consumed
+    Warning,
+    /// 90% of the reserved blocks are consumed
+    Urgent,
+}
+
+impl From<u8> for PreEolInfo {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Normal,
+            2 => Self::Warning,
+            3 => Self::Urgent,
+            _ => Self::Undefined,
+        }
+    }
+}
+
+/// Ext CSD FLUSH_CACHE byte, written with 1 to flush the device cache.
+pub const EXT_CSD_FLUSH_CACHE: u8 = 32;
+/// Ext CSD CACHE_CTRL byte, bit 0 turns the device cache on.
+pub const EXT_CSD_CACHE_CTRL: u8 = 33;
+/// Ext CSD BUS_WIDTH byte: 0 for 1-bit, 1 for 4-bit, 2 for 8-bit.
+pub const EXT_CSD_BUS_WIDTH: u8 = 183;
+/// Ext CSD HS_TIMING byte, 1 selects high speed.
+pub const EXT_CSD_HS_TIMING: u8 = 185;
+
+/// eMMC Extended CSD register, the 512 byte block returned by CMD8.
+#[derive(Clone, Copy)]
+pub struct ExtCsd([u8; 512]);
+
+impl From<[u8; 512]> for ExtCsd {
+    fn from(value: [u8; 512]) -> Self {
+        Self(value)
+    }
+}
+
+impl ExtCsd {
+    pub fn pre_eol_info(&self) -> PreEolInfo {
+        PreEolInfo::from(self.0[267])
+    }
+
+    /// DEVICE_LIFE_TIME_EST_TYP_A: SLC lifetime used in 10% steps, 0x0B once
+    /// the estimate is exceeded, 0 if not reported.
+    pub fn life_time_est_a(&self) -> u8 {
+        self.0[268]
+    }
+
+    /// DEVICE_LIFE_TIME_EST_TYP_B: same as type A for MLC memory.
+    pub fn life_time_est_b(&self) -> u8 {
+        self.0[269]
+    }
+
+    /// HPI_FEATURES bit 0: the device implements High Priority Interrupt.
+    pub fn hpi_supported(&self) -> bool {
+        self.0[503] & 1 != 0
+    }
+
+    /// HPI_FEATURES bit 1: HPI is sent as CMD12 instead of CMD13.
+    pub fn hpi_uses_cmd12(&self) -> bool {
+        self.0[503] & 2 != 0
+    }
+
+    /// HPI_MGMT bit 0: HPI has been enabled by the host.
+    pub fn hpi_enabled(&self) -> bool {
+        self.0[161] & 1 != 0
+    }
+
+    /// SEC_COUNT: device capacity in 512-byte sectors, 0 for devices up to
+    /// 2 GB, whose size is in the CSD.
+    pub fn sec_count(&self) -> u32 {
+        u32::from_le_bytes([self.0[212], self.0[213], self.0[214], self.0[215]])
+    }
+
+    /// DEVICE_TYPE bit 1: high speed at 52 MHz.
+    pub fn high_speed_52(&self) -> bool {
+        self.0[196] & 2 != 0
+    }
+
+    /// CACHE_SIZE: size of the device cache in KiB, 0 without a cache.
+    pub fn cache_size_kib(&self) -> u32 {
+        u32::from_le_bytes([self.0[249], self.0[250], self.0[251], self.0[252]])
+    }
+
+    /// CACHE_CTRL bit 0: the device cache is turned on.
+    pub fn cache_enabled(&self) -> bool {
+        self.0[EXT_CSD_CACHE_CTRL as usize] & 1 != 0
+    }
+
+    /// Update the CACHE_CTRL byte of this copy after a SWITCH wrote it.
+    pub(crate) fn set_cache_enabled(&mut self, enabled: bool) {
+        self.0[EXT_CSD_CACHE_CTRL as usize] = enabled as u8;
+    }
+
+    /// OUT_OF_INTERRUPT_TIME: longest time to leave an interrupted operation
+    /// after HPI, in milliseconds.
+    pub fn out_of_interrupt_time_ms(&self) -> u64 {
+        u64::from(self.0[198]) * 10
+    }
+}
+
+impl Debug for ExtCsd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EXT_CSD: Extended CSD")
+            .field("Sector Count", &self.sec_count())
+            .field("Pre EOL Info", &self.pre_eol_info())
+            .field("Life Time Estimate A", &self.life_time_est_a())
+            .field("Life Time Estimate B", &self.life_time_est_b())
+            .field("HPI Supported", &self.hpi_supported())
+            .field("HPI Enabled", &self.hpi_enabled())
+            .field("Cache Size (KiB)", &self.cache_size_kib())
+            .field("Cache Enabled", &self.cache_enabled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// R2 response words of a 128-bit register, RESP0 first.
+    fn words(reg: u128) -> (u32, u32, u32, u32) {
+        (
+            reg as u32,
+            (reg >> 32) as u32,
+            (reg >> 64) as u32,
+            (reg >> 96) as u32,
+        )
+    }
+
+    // CSD 1.0 of a 2 GB SDSC card: C_SIZE 0xF16, C_SIZE_MULT 7, READ_BL_LEN 10
+    const CSD_SDSC_2G: u128 = 0x0026_0032_5F5A_83C5_B6DB_FF80_1280_40ED;
+    // CSD 2.0 of a 32 GB SDHC card: C_SIZE 0xEDC8
+    const CSD_SDHC_32G: u128 = 0x400E_0032_5B59_0000_EDC8_7F80_0A40_40C3;
+
+    #[test]
+    fn csd_v1_capacity() {
+        let csd = Csd::from(words(CSD_SDSC_2G));
+        assert!(csd.is_valid());
+        assert_eq!(csd.version(), 0);
+        assert_eq!(csd.block_length(), BlockSize::B1024);
+        assert_eq!(csd.block_count(), 3863 * 512);
+        assert_eq!(csd.card_size(), 2_025_324_544);
+        assert_eq!(csd.r2w_factor(), 4);
+        assert_eq!(csd.erase_size_blocks(), 1);
+        assert!(!csd.perm_write_protect() && !csd.tmp_write_protect());
+        assert!(!csd.wp_group_enabled());
+    }
+
+    #[test]
+    fn csd_v2_capacity() {
+        let csd = Csd::from(words(CSD_SDHC_32G));
+        assert!(csd.is_valid());
+        assert_eq!(csd.version(), 1);
+        assert_eq!(csd.transfer_rate(), 0x32);
+        assert_eq!(csd.block_count(), (0xEDC8 + 1) * 1024);
+        assert_eq!(csd.card_size(), 31_914_983_424);
+    }
+
+    #[test]
+    fn csd_crc_mismatch() {
+        let csd = Csd::from(words(CSD_SDHC_32G ^ 1 << 48));
+        assert!(!csd.crc_matches());
+        assert!(!csd.is_valid());
+    }
+
+    // MID 0x03, OID "SD", PNM "SU02G", PRV 8.0, PSN 0x12345678, MDT 2013-06
+    const CID_SD: u128 = 0x0353_4453_5530_3247_8012_3456_7800_D619;
+
+    #[test]
+    fn cid_fields() {
+        let cid = Cid::from(words(CID_SD));
+        assert!(cid.is_valid());
+        assert_eq!(cid.manufacturer_id(), 0x03);
+        assert_eq!(&cid.oem_id(), b"SD");
+        assert_eq!(&cid.product_name(), b"SU02G");
+        assert_eq!(cid.product_revision(), (8, 0));
+        assert_eq!(cid.serial_number(), 0x1234_5678);
+        assert_eq!(cid.manufacturing_date(), (2013, 6));
+        assert_eq!(cid.crc7(), 0x0C);
+        assert!(Cid::from(CID_SD) == cid);
+    }
+
+    #[test]
+    fn cid_crc_mismatch() {
+        assert!(!Cid::from(CID_SD ^ 1 << 24).is_valid());
+    }
+
+    #[test]
+    fn sd_status_of_uhs_card() {
+        // 4-bit bus, class 10, AU 4 MiB, U3, V30, A2, discard support
+        let mut block = [0u8; 64];
+        block[..28].copy_from_slice(&[
+            0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x04, 0x00, 0x90, 0x00, 0x0F, 0x05,
+            0x3A, 0x1E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        ]);
+        let status = SdStatus::from(block);
+        assert_eq!(status.bus_width(), BusWidth::Four);
+        assert!(!status.secure_mode());
+        assert_eq!(status.sd_memory_card_type(), 0);
+        assert_eq!(status.protected_area_size(), 0x0800_0000);
+        assert_eq!(status.speed_class(), 4);
+        assert_eq!(status.move_performance(), 0);
+        assert_eq!(status.allocation_unit_size(), 9);
+        assert_eq!(status.erase_size(), 0x000F);
+        assert_eq!(status.erase_timeout(), 1);
+        assert_eq!(status.erase_offset(), 1);
+        assert_eq!(status.au_size_bytes(), Some(4 << 20));
+        // 1 s per 15 AUs of 4 MiB, plus 1 s
+        assert_eq!(status.erase_timeout_ms(8192), Some(1_066));
+        assert_eq!(status.erase_timeout_ms(30 << 13), Some(3_000));
+        assert_eq!(status.uhs_speed_grade(), 3);
+        assert_eq!(status.video_speed_class(), 30);
+        assert_eq!(status.app_perf_class(), 2);
+        assert!(status.discard_support());
+    }
+
+    #[test]
+    fn switch_status_of_high_speed_check() {
+        // CMD6 check mode selecting high speed: 100 mA, group 1 supports
+        // default and high speed, every other group only its default
+        let mut block = [0u8; 64];
+        block[..17].copy_from_slice(&[
+            0x00, 0x64, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x03,
+            0x00, 0x00, 0x01,
+        ]);
+        let status = SwitchStatus::from(block);
+        assert_eq!(status.max_current_ma(), 100);
+        assert!(status.supported(1, 0) && status.supported(1, 1));
+        assert!(!status.supported(1, 2));
+        assert!(status.supported(4, 0) && !status.supported(4, 1));
+        assert_eq!(status.selected(1), 1);
+        (2..=6).for_each(|group| assert_eq!(status.selected(group), 0));
+    }
+
+    #[test]
+    fn switch_status_rejected_function() {
+        let mut block = [0u8; 64];
+        block[14..17].copy_from_slice(&[0xF0, 0x00, 0x0F]);
+        let status = SwitchStatus::from(block);
+        assert_eq!(status.selected(6), 0xF);
+        assert_eq!(status.selected(1), 0xF);
+        assert_eq!(status.selected(3), 0);
+    }
+
+    #[test]
+    fn scr_of_sdhc_card() {
+        let scr = Scr::from([0x02, 0x35, 0x80, 0x43, 0, 0, 0, 0]);
+        assert!(scr.is_valid());
+        assert_eq!(scr.version(), SDSpecVersion::V5);
+        assert!(scr.bus_width_one() && scr.bus_width_four());
+        assert_eq!(scr.data_stat_after_erase(), 0);
+        assert!(scr.cmd23_supported());
+        assert_eq!(scr.0, Scr::from((0, 0x0235_8043)).0);
+    }
+
+    #[test]
+    fn scr_of_sd_1_0_card() {
+        let scr = Scr::from([0x00, 0xA5, 0x00, 0x00, 0, 0, 0, 0]);
+        assert!(scr.is_valid());
+        assert_eq!(scr.version(), SDSpecVersion::V1_0);
+        assert_eq!(scr.data_stat_after_erase(), 1);
+        assert!(!scr.cmd23_supported());
+        assert!(!Scr::from([0x10, 0, 0, 0, 0, 0, 0, 0]).is_valid());
+    }
+}